@@ -0,0 +1,433 @@
+//! Event-driven connection handling
+//!
+//! In `IoMode::Events`, plain HTTP connections are parked in an epoll event
+//! loop (mio) while they are idle, instead of blocking a worker for the whole
+//! keep-alive timeout. When a request arrives, the connection is sent to the
+//! thread pool, which reads and answers it, then gives it back to the loop.
+//!
+//! The loop never waits for the pool. When its queue is full, connections with
+//! a request stay parked and are sent again on the next tick, or are rejected
+//! with 503 under `OverflowPolicy::Reject`.
+//!
+//! HTTPS listeners always use one worker per connection, for decrypted bytes
+//! buffered by OpenSSL can not be seen by epoll.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use super::{Config, ThreadPool, handle_request, listener};
+use super::parser::http::reader::RequestReader;
+use listener::{Connection, ListenerConfig, ListenerKind, OverflowPolicy};
+
+/// How connections are waited for
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IoMode {
+    /// One worker per connection, blocked while waiting for the next request
+    Threads,
+    /// Idle connections wait in an event loop, workers only handle requests
+    Events,
+    /// Tokio runtime, rhttp must be built with the `tokio-backend` feature
+    Async,
+}
+
+/// Token of the waker, listeners and connections use the small ones
+const WAKER: Token = Token(usize::MAX);
+/// How often idle connections are checked for timeout
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often requests waiting for a full queue are sent again
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Connection which can be parked in the event loop
+pub trait EventConnection: Connection + AsRawFd {
+    /// Another handle of the connection, used to send 503 when the pool is full
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Write>>;
+}
+
+impl EventConnection for TcpStream {
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Write>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl EventConnection for UnixStream {
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Write>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl Connection for Box<dyn EventConnection> {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(dur)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+}
+
+type Reader = RequestReader<Box<dyn EventConnection>>;
+
+/// Non-blocking listener watched by the event loop
+pub enum EventListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl EventListener {
+    fn accept(&self) -> io::Result<Box<dyn EventConnection>> {
+        match self {
+            EventListener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                // accepted socket must block, workers read it as usual
+                stream.set_nonblocking(false)?;
+                Ok(Box::new(stream))
+            }
+            EventListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            EventListener::Tcp(listener) => listener.as_raw_fd(),
+            EventListener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Bind the socket(s) of a plain HTTP listener for the event loop
+pub fn bind(listener_cfg: &ListenerConfig) -> io::Result<Vec<EventListener>> {
+    let listeners = match listener_cfg.kind {
+        ListenerKind::Tcp => listener::bind_all(listener_cfg)?
+            .into_iter()
+            .map(|(addr, listener)| {
+                println!("listening on {} (http, events)", addr);
+                EventListener::Tcp(listener)
+            })
+            .collect(),
+        ListenerKind::Unix => {
+            let listener = listener::bind_unix(listener_cfg)?;
+            println!("listening on unix:{} (http, events)", listener_cfg.path);
+            vec![EventListener::Unix(listener)]
+        }
+    };
+    for listener in &listeners {
+        match listener {
+            EventListener::Tcp(listener) => listener.set_nonblocking(true)?,
+            EventListener::Unix(listener) => listener.set_nonblocking(true)?,
+        }
+    }
+    Ok(listeners)
+}
+
+/// Idle connection waiting for its next request
+struct Parked {
+    reader: Reader,
+    /// Connection is closed if no request comes before it
+    deadline: Instant,
+}
+
+struct EventLoop {
+    poll: Poll,
+    waker: Arc<Waker>,
+    listeners: Vec<EventListener>,
+    parked: HashMap<Token, Parked>,
+    /// Parked connections with a request, waiting for room in the queue of pool
+    waiting: VecDeque<Token>,
+    next_token: usize,
+    /// Connections given back by workers
+    returned_tx: mpsc::Sender<Reader>,
+    returned_rx: mpsc::Receiver<Reader>,
+    last_sweep: Instant,
+    cfg: Config,
+    pool: Arc<ThreadPool>,
+}
+
+/// Watch `listeners` and their connections in a new thread
+///
+/// Requests are handled in `pool`.
+pub fn spawn_event_loop(listeners: Vec<EventListener>, cfg: &Config, pool: Arc<ThreadPool>) -> io::Result<thread::JoinHandle<()>> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    for (i, listener) in listeners.iter().enumerate() {
+        poll.registry().register(&mut SourceFd(&listener.as_raw_fd()), Token(i), Interest::READABLE)?;
+    }
+    let (returned_tx, returned_rx) = mpsc::channel();
+    let event_loop = EventLoop {
+        poll,
+        waker,
+        next_token: listeners.len(),
+        listeners,
+        parked: HashMap::new(),
+        waiting: VecDeque::new(),
+        returned_tx,
+        returned_rx,
+        last_sweep: Instant::now(),
+        cfg: cfg.clone(),
+        pool,
+    };
+    Ok(thread::spawn(move || event_loop.run()))
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = if self.waiting.is_empty() { SWEEP_INTERVAL } else { RETRY_INTERVAL };
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue
+                }
+                println!("event loop failed: {}", e);
+                return
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.take_returned(),
+                    Token(i) if i < self.listeners.len() => self.accept(i),
+                    token => {
+                        self.dispatch(token);
+                    }
+                }
+            }
+            self.retry_waiting();
+            if self.last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.sweep();
+            }
+        }
+    }
+
+    /// Accept all pending connections of listener `i`
+    fn accept(&mut self, i: usize) {
+        loop {
+            match self.listeners[i].accept() {
+                Ok(stream) => {
+                    let mut reader = RequestReader::new(stream);
+                    reader.set_max_body_size(self.cfg.max_body_size);
+                    self.park(reader);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("fail to accept connection: {}", e);
+                    break
+                }
+            }
+        }
+    }
+
+    /// Wait for the next request of `reader`
+    fn park(&mut self, reader: Reader) {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        let fd = reader.get_ref().as_raw_fd();
+        // data received before registering is reported at once
+        if let Err(e) = self.poll.registry().register(&mut SourceFd(&fd), token, Interest::READABLE) {
+            println!("fail to watch connection: {}, close it.", e);
+            return
+        }
+        let deadline = Instant::now() + Duration::from_secs(self.cfg.timeout as u64);
+        self.parked.insert(token, Parked { reader, deadline });
+    }
+
+    fn take_returned(&mut self) {
+        while let Ok(reader) = self.returned_rx.try_recv() {
+            self.park(reader);
+        }
+    }
+
+    /// Request of connection `token` arrives, handle it in pool
+    ///
+    /// Return false if the queue of pool is full, connection is kept in `waiting`.
+    fn dispatch(&mut self, token: Token) -> bool {
+        let parked = match self.parked.remove(&token) {
+            Some(parked) => parked,
+            None => return true, // closed by sweep
+        };
+        // connection is watched again after the request, or never if it waits
+        let fd = parked.reader.get_ref().as_raw_fd();
+        let _ = self.poll.registry().deregister(&mut SourceFd(&fd));
+        let reply = parked.reader.get_ref().try_clone_writer().ok();
+        let returned_tx = self.returned_tx.clone();
+        let waker = Arc::clone(&self.waker);
+        let cfg = self.cfg.clone();
+        // a job dropped by a full queue leaves the connection here
+        let slot = Arc::new(Mutex::new(Some(parked.reader)));
+        let slot_cp = Arc::clone(&slot);
+        let job = move || {
+            let reader = slot_cp.lock().ok().and_then(|mut reader| reader.take());
+            if let Some(reader) = reader.and_then(|reader| serve(reader, &cfg)) {
+                if returned_tx.send(reader).is_ok() {
+                    let _ = waker.wake();
+                }
+            }
+        };
+        match self.cfg.overflow {
+            OverflowPolicy::Block => {
+                if self.pool.try_execute(job) {
+                    return true
+                }
+            }
+            OverflowPolicy::Reject => {
+                listener::dispatch(&self.pool, &self.cfg, reply, job);
+                return true
+            }
+        }
+        let reader = match slot.lock().ok().and_then(|mut reader| reader.take()) {
+            Some(reader) => reader,
+            None => return true,
+        };
+        self.parked.insert(token, Parked { reader, deadline: parked.deadline });
+        if !self.waiting.contains(&token) {
+            self.waiting.push_back(token);
+        }
+        false
+    }
+
+    /// Send waiting requests in order, until the queue of pool is full again
+    fn retry_waiting(&mut self) {
+        while let Some(&token) = self.waiting.front() {
+            if !self.dispatch(token) {
+                return
+            }
+            self.waiting.pop_front();
+        }
+    }
+
+    /// Close connections which are idle for too long
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        self.last_sweep = now;
+        // waiting connections have a request, they are not idle
+        let expired: Vec<Token> = self.parked.iter()
+            .filter(|(token, parked)| parked.deadline <= now && !self.waiting.contains(token))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if let Some(parked) = self.parked.remove(&token) {
+                let _ = self.poll.registry().deregister(&mut SourceFd(&parked.reader.get_ref().as_raw_fd()));
+            }
+        }
+    }
+}
+
+/// Handle the ready request of `reader`, and the pipelined ones after it
+///
+/// Return the connection if it should be kept alive.
+fn serve(mut reader: Reader, cfg: &Config) -> Option<Reader> {
+    let timeout: u64 = cfg.timeout as u64;
+    // the rest of request may come later
+    if reader.get_ref().set_read_timeout(Some(Duration::new(timeout, 0))).is_err() {
+        return None
+    }
+    loop {
+        if !handle_request(&mut reader, None, cfg) {
+            return None
+        }
+        // socket is not readable for bytes already received, empty lines are ignored
+        if reader.buffered().iter().all(|&b| b == b'\r' || b == b'\n') {
+            return Some(reader)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read a response head, HEAD response has no body
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte).unwrap() == 0 {
+                break
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn idle_connections_do_not_block_workers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let cfg = Config {
+            timeout: 2,
+            ..crate::tests::test_config()
+        };
+        // a single worker, idle keep-alive connections would starve it in threads mode
+        let pool = Arc::new(ThreadPool::new(1, 1, 1, None));
+        spawn_event_loop(vec![EventListener::Tcp(listener)], &cfg, pool).unwrap();
+
+        let mut clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for client in &clients {
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        }
+        for _ in 0..2 {
+            for client in clients.iter_mut().rev() {
+                client.write_all(b"HEAD /hello.html HTTP/1.1\r\n\r\n").unwrap();
+                assert!(read_head(client).starts_with("HTTP/1.1 200 OK\r\n"));
+            }
+        }
+        // pipelined requests are answered in order
+        clients[0].write_all(b"HEAD /hello.html HTTP/1.1\r\n\r\nHEAD /none HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_head(&mut clients[0]).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(read_head(&mut clients[0]).starts_with("HTTP/1.1 404"));
+
+        // idle connection is closed after timeout
+        let mut byte = [0u8; 1];
+        assert_eq!(clients[1].read(&mut byte).unwrap(), 0);
+    }
+
+    #[test]
+    fn full_queue_does_not_block_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let cfg = Config {
+            timeout: 1,
+            ..crate::tests::test_config()
+        };
+        // a single worker and a single slot in queue, both taken
+        let pool = Arc::new(ThreadPool::new(1, 1, 1, None));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        pool.execute(|| ());
+        spawn_event_loop(vec![EventListener::Tcp(listener)], &cfg, pool).unwrap();
+
+        let mut waiting = TcpStream::connect(addr).unwrap();
+        waiting.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        waiting.write_all(b"HEAD /hello.html HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        // loop still sweeps idle connections while the request waits
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut byte = [0u8; 1];
+        assert_eq!(idle.read(&mut byte).unwrap(), 0);
+
+        // waiting request is answered once workers are free, not closed as idle
+        drop(release_tx);
+        assert!(read_head(&mut waiting).starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,643 @@
+//! RHTTP as a library
+//!
+//! Everything of the server except its command line. Other programs can
+//! build a `Config` and run the listeners themselves, or embed the async
+//! server in their own tokio runtime by `tokio_backend::serve`.
+
+use std::io::prelude::*;
+// use std::thread;
+// use std::time::Duration;
+// use std::rc::Rc;
+
+pub mod tpool;
+pub use tpool::*;
+
+pub mod listener;
+use listener::{Connection, ListenerConfig, OverflowPolicy};
+
+pub mod tls;
+use tls::TlsConfig;
+
+pub mod event;
+use event::IoMode;
+
+#[cfg(feature = "tokio-backend")]
+pub mod tokio_backend;
+
+pub mod parser; // parser for http head
+pub use parser::http::*; // import http head data structure
+use parser::http::reader::RequestReader;
+use parser::http::method::utils::ChunkedWriter;
+
+extern crate confy;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+/// Request buffer size
+pub const BUFFER_SIZE: usize = 32768;
+/// Default page root path
+// pub const DEFAULT_ROOT: &str = "/mnt/c/Workpath/rhttp/page";
+pub const DEFAULT_ROOT: &str = "/home/lfz/Videos/rhttp/page";
+
+/// Global config file, shared by all threads
+/// 
+/// Missing fields in config file will use default value.
+/// 
+/// By default one plain HTTP listener is served on `127.0.0.1:7878`,
+/// HTTPS must be enabled by `tls = true` of a listener.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// deprecated, port of the first listener, use `listeners` instead
+    /// 
+    /// Old config files still work, it is moved to the first listener by `upgrade`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u32>,
+    /// max number of threads created in the thread pool
+    pub thread_number: usize,
+    /// number of threads kept in the thread pool even if they are idle
+    pub min_threads: usize,
+    /// idle threads above `min_threads` exit after `idle_timeout` secs, 0 means never
+    pub idle_timeout: u64,
+    /// max number of accepted connections waiting for a free thread
+    pub queue_size: usize,
+    /// what to do with new connections when the queue is full
+    pub overflow: OverflowPolicy,
+    /// `Retry-After` secs sent with 503 when connection is rejected
+    pub retry_after: u64,
+    /// how idle keep-alive connections wait, HTTPS ones always use threads
+    pub io_mode: IoMode,
+    /// file root dir
+    pub root_dir: String, 
+    /// timeout unit: secs
+    pub timeout: i64, 
+    /// how long async io mode waits for each read of a started request, unit: secs, 0 means never
+    /// 
+    /// `timeout` only applies to idle keep-alive connections there.
+    pub request_timeout: u64,
+    /// enable chunk resp, chunk req is always supported
+    /// 
+    /// Body of unknown length is always chunked.
+    pub chunk: bool, 
+    /// max size of each chunk in chunk resp, unit: bytes
+    pub chunk_size: usize,
+    /// max request body size, unit: bytes
+    pub max_body_size: u64,
+    /// follow symlinks which point outside root dir
+    pub follow_symlinks: bool,
+    /// certificate, key and ciphers of HTTPS listeners
+    pub tls: TlsConfig,
+    /// listeners, each of them serves HTTP or HTTPS on a port or a Unix socket
+    /// 
+    /// Tables must be placed after values in TOML, keep it at the end.
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self { Self {
+        port: None,
+        thread_number: 64,
+        min_threads: 4,
+        idle_timeout: 60,
+        queue_size: 64,
+        overflow: OverflowPolicy::Block,
+        retry_after: 1,
+        io_mode: IoMode::Threads,
+        root_dir: DEFAULT_ROOT.into(),
+        timeout: 1,
+        request_timeout: 30,
+        chunk: false,
+        chunk_size: parser::http::method::utils::chunk::DEFAULT_CHUNK_SIZE,
+        max_body_size: 128 * 1024 * 1024,
+        follow_symlinks: false,
+        tls: TlsConfig::default(),
+        listeners: vec![ListenerConfig::default()],
+    } }
+}
+
+impl Config {
+    /// Move deprecated fields of an old config file to their new place
+    pub fn upgrade(&mut self) {
+        if let Some(port) = self.port.take() {
+            println!("warning: `port` is deprecated, it is set as port of the first listener, use `listeners` instead");
+            println!("warning: the first listener serves plain HTTP unless it has `tls = true`, HTTPS was the only choice before");
+            match self.listeners.first_mut() {
+                Some(listener) => listener.port = port,
+                None => self.listeners.push(ListenerConfig { port, ..Default::default() }),
+            }
+        }
+    }
+}
+
+/// Main function to handle http connection
+/// 
+/// When a new TCP link established, give it to handle_connection in a free worker.
+/// 
+/// Plain TCP and TLS connections are handled the same way.
+/// 
+/// Pipelined requests are supported: bytes received after a request are kept
+/// by `RequestReader` and parsed as the next request, responses are written in order.
+/// 
+/// Returning from this function will close TCP link.
+fn handle_connection<C: Connection>(stream: C, cfg: Config) {
+    let timeout: u64 = cfg.timeout as u64;
+    // client certificate does not change in a connection
+    let client_subject = stream.client_subject();
+    if let Some(subject) = &client_subject {
+        println!("client certificate: {}", subject);
+    }
+    // request may be split across many reads, reader will accumulate it
+    let mut reader = RequestReader::new(stream);
+    reader.set_max_body_size(cfg.max_body_size);
+    while handle_request(&mut reader, client_subject.as_deref(), &cfg) {
+        // setup tcp timeout and wait for next request,
+        // pipelined request already in buffer will be handled without waiting
+        if reader.get_ref().set_read_timeout(Some(std::time::Duration::new(timeout, 0))).is_err() {
+            return
+        }
+    }
+}
+
+/// Read one request from connection, write its response back
+/// 
+/// Malformed request is answered with a 4xx/5xx response, then connection is closed.
+/// 
+/// Return true if connection should be kept alive for next request.
+fn handle_request<S: Connection>(reader: &mut RequestReader<S>, client_subject: Option<&str>, cfg: &Config) -> bool {
+    let head = match reader.read_request() {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            // client closed TCP link
+            return false
+        }
+        Err(e) => match ParseError::from_io(&e) {
+            Some(parse_error) => {
+                // tell client why its request is rejected, then close TCP link
+                println!("reject malformed request: {}", parse_error);
+                let response = HttpResponse::from_parse_error(parse_error);
+                send_response(reader.get_mut(), response, false, false, cfg);
+                linger(reader.get_mut());
+                return false
+            }
+            None => {
+                // TCP timeout or broken link, close TCP link
+                println!("fail to read request: {}, close TCP link.", e);
+                return false
+            }
+        }
+    };
+    
+    // request head is ASCII, log it lossily
+    println!("Raw request head:\n{}", String::from_utf8_lossy(&head));
+    
+    // parse http request, head has been checked by reader
+    let mut request = match HttpRequest::from_parts(&head, Box::new(std::io::empty())) {
+        Ok(request) => request,
+        Err(parse_error) => {
+            let response = HttpResponse::from_parse_error(&parse_error);
+            send_response(reader.get_mut(), response, false, false, cfg);
+            linger(reader.get_mut());
+            return false
+        }
+    };
+    request.client_subject = client_subject.map(|i| i.to_string());
+    // body is streamed from connection as raw bytes
+    request.body = Box::new(reader.body());
+    // println!("{}", request);
+    
+    let keep_alive = request.keep_alive();
+    // HTTP/1.0 client does not understand chunked body
+    let allow_chunked = request.version == "HTTP/1.1";
+    
+    // generate http response according to require type
+    let response = HttpResponse::new(&mut request, cfg);
+    // release body stream, response is written to the same connection
+    drop(request);
+    if !reader.trailers().is_empty() {
+        println!("request trailers: {:#?}", reader.trailers());
+    }
+    match response {
+        Some(response) => send_response(reader.get_mut(), response, keep_alive, allow_chunked, cfg),
+        None => false // TCP will also be closed
+    }
+}
+
+/// Fill connection related headers, write response to stream
+/// 
+/// Return true if connection should be kept alive for next request.
+fn send_response<S: Write>(stream: &mut S, mut response: HttpResponse, keep_alive: bool, allow_chunked: bool, cfg: &Config) -> bool {
+    let (keep_alive, chunked) = prepare_response(&mut response, keep_alive, allow_chunked, cfg);
+    if let Err(e) = write_response(stream, response, chunked, cfg) {
+        println!("fail to send response: {}, close TCP link.", e);
+        return false
+    }
+    println!("response send at {}.", std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs());
+    keep_alive
+}
+
+/// Max bytes drained from a rejected connection before closing it
+const LINGER_SIZE: usize = 1024 * 1024;
+/// Max time of draining a rejected connection
+const LINGER_TIME: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Close a connection gracefully after rejecting its request
+/// 
+/// Closing with unread input makes the kernel reset the connection, and client
+/// may lose the response. Input is drained until client closes its side,
+/// at most `LINGER_SIZE` bytes in `LINGER_TIME`.
+fn linger<S: Connection>(stream: &mut S) {
+    if stream.shutdown_write().is_err() {
+        return
+    }
+    let deadline = std::time::Instant::now() + LINGER_TIME;
+    let mut buf = [0u8; 4096];
+    let mut drained = 0;
+    while drained < LINGER_SIZE {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.as_nanos() == 0 || stream.set_read_timeout(Some(remaining)).is_err() {
+            return
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(size) => drained += size,
+        }
+    }
+}
+
+/// Fill connection related headers
+/// 
+/// Body is chunked if `Config.chunk` is enabled or its length is unknown,
+/// unless `allow_chunked` is false.
+/// 
+/// Return `(keep_alive, chunked)`.
+fn prepare_response(response: &mut HttpResponse, mut keep_alive: bool, allow_chunked: bool, cfg: &Config) -> (bool, bool) {
+    let timeout: u64 = cfg.timeout as u64;
+
+    // empty body is never chunked, for HEAD and 204 response must not have body
+    let chunked = allow_chunked && !response.body.is_empty() && (cfg.chunk || response.body.len().is_none());
+    if chunked {
+        response.headers.remove("Content-Length");
+        response.headers.insert("Transfer-Encoding", "chunked");
+        if !response.trailers.is_empty() {
+            let names: Vec<&str> = response.trailers.iter().map(|(k, _)| k).collect();
+            response.headers.insert("Trailer", names.join(", "));
+        }
+    } else {
+        match response.body.len() {
+            // 1xx and 204 response must not have Content-Length
+            // ref: https://tools.ietf.org/html/rfc7230#section-3.3.2
+            _ if response.status_code < 200 || response.status_code == 204 => {
+                response.headers.remove("Content-Length");
+            }
+            Some(len) => response.headers.insert("Content-Length", len.to_string()),
+            None => {
+                // body of unknown length ends when connection is closed
+                keep_alive = false;
+                response.headers.insert("Connection", "close");
+            }
+        }
+    }
+
+    // setup Keep-Alive: timeout
+    response.headers.insert("Keep-Alive", format!("timeout={}", timeout));
+    // if headers.Connection not assigned, assign it automaticly
+    if let Some(resp_keep_alive) = response.headers.get("Connection") {
+        keep_alive = keep_alive && resp_keep_alive.to_lowercase() == "keep-alive";
+    } else {
+        let connection_value = if keep_alive { "keep-alive" } else { "close" };
+        response.headers.insert("Connection", connection_value);
+        println!("keep_alive: {}", keep_alive);
+    }
+    println!("{}\n", response);
+    (keep_alive, chunked)
+}
+
+/// Write head and body of a prepared response to stream
+fn write_response<S: Write>(stream: &mut S, mut response: HttpResponse, chunked: bool, cfg: &Config) -> std::io::Result<()> {
+    // generate final headers
+    let resp_string = response.generate_head_string();
+
+    println!("resp content head:\n{}\n", resp_string);
+    let trailers = std::mem::take(&mut response.trailers);
+    stream.write_all(resp_string.as_bytes())?;
+    if chunked {
+        let mut writer = ChunkedWriter::new(&mut *stream, cfg.chunk_size);
+        response.body.write_to(&mut writer)?;
+        writer.finish(&trailers)?;
+    } else {
+        response.body.write_to(stream)?;
+    }
+    stream.flush()
+}
+    
+#[cfg(test)]
+mod tests {
+    //! # Tips
+    //! 
+    //! Run
+    //! ```
+    //! cargo test -- --nocapture --test <test_name>
+    //! ```
+    //! to check response in console.
+    //! 
+    //! For example:
+    //! 
+    //! ```
+    //! cargo test -- --nocapture --test post_test
+    //! ```
+    
+    use super::*;
+
+    /// Config used by unittest, pages are served from `page` in this repo
+    pub(crate) fn test_config() -> Config {
+        Config {
+            root_dir: concat!(env!("CARGO_MANIFEST_DIR"), "/page").to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Config used by unittest which writes files, use a temp dir as root
+    pub(crate) fn test_upload_config(name: &str) -> Config {
+        let root_dir = std::env::temp_dir().join(format!("rhttp_test_{}", name));
+        std::fs::create_dir_all(&root_dir).unwrap();
+        Config {
+            root_dir: root_dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Generate response string accoding to input request string
+    /// 
+    /// It is a copy of handle_connection, we use &str as input. 
+    /// Thus we do not to deal with TCP in this unittest.
+    /// 
+    /// * All TCP-related ops are removed.
+    /// * Keep-Alive will be ignored.
+    fn resp_from_req_str(input: &str, cfg: &Config) -> String {
+        // parse http request
+        let mut reader = RequestReader::new(input.as_bytes());
+        reader.set_max_body_size(cfg.max_body_size);
+        let head = reader.read_request().unwrap().unwrap();
+        let mut request = HttpRequest::from_parts(&head, Box::new(reader.body())).unwrap();
+        
+        let keep_alive = request.keep_alive();
+
+        // generate http response according to require type
+        match HttpResponse::new(&mut request, cfg) {
+            Some(mut response) => {
+                // setup Keep-Alive: timeout
+                response.headers.insert("Keep-Alive".to_string(), format!("timeout={}", 4));
+                // if headers.Connection not assigned, assign it automaticly
+                if !response.headers.contains_key("Connection") {
+                    let connection_value = if keep_alive { "keep_alive" } else { "close" };
+                    response.headers.insert("Connection".to_string(), connection_value.to_string());
+                    println!("keep_alive: {}", keep_alive);
+                }
+                println!("{}\n", response);
+                println!("response generated at {}.", std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs());
+                response.generate_string()
+            }
+            _ => {
+                panic!("server rejected to generate response, tcp cloned");
+            }
+        }
+    }
+
+    /// In-memory connection, reads from `input`, writes to `output`
+    struct MockStream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(input: &[u8]) -> Self {
+            MockStream { input: std::io::Cursor::new(input.to_vec()), output: Vec::new() }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Connection for MockStream {
+        fn set_read_timeout(&self, _dur: Option<std::time::Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn shutdown_write(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Status lines of all responses written to a stream
+    pub(crate) fn status_lines(output: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(output).lines()
+            .filter(|line| line.starts_with("HTTP/1.1 "))
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// Test pipelined requests in one connection
+    #[test]
+    fn pipeline_test () {
+        let raw_req = "HEAD /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+            OPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+            GET /readme.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n\
+            GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let cfg = test_config();
+        let mut reader = RequestReader::new(MockStream::new(raw_req.as_bytes()));
+        while handle_request(&mut reader, None, &cfg) {}
+        // request after `Connection: close` is not handled
+        assert_eq!(status_lines(&reader.get_ref().output), ["HTTP/1.1 200 OK", "HTTP/1.1 204 No Content", "HTTP/1.1 200 OK"]);
+    }
+
+    /// Test binary file is sent byte-for-byte
+    #[test]
+    fn binary_get_test () {
+        let cfg = test_config();
+        let mut reader = RequestReader::new(MockStream::new(b"GET /test.jpg HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert!(!handle_request(&mut reader, None, &cfg));
+        let output = &reader.get_ref().output;
+        let expected = std::fs::read(format!("{}/test.jpg", cfg.root_dir)).unwrap();
+        let (_, body_start) = find_head_end(output).unwrap();
+        assert_eq!(&output[body_start..], &expected[..]);
+        assert!(String::from_utf8_lossy(&output[..body_start]).contains(&format!("Content-Length: {}", expected.len())));
+    }
+
+    /// Test chunked response can be decoded to the original file
+    #[test]
+    fn chunked_get_test () {
+        let cfg = Config {
+            chunk: true,
+            chunk_size: 100,
+            ..test_config()
+        };
+        let mut reader = RequestReader::new(MockStream::new(b"GET /test.jpg HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert!(!handle_request(&mut reader, None, &cfg));
+        let output = &reader.get_ref().output;
+        let (head_len, body_start) = find_head_end(output).unwrap();
+        let head = String::from_utf8_lossy(&output[..head_len]);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        // decode chunked body by request reader
+        let mut raw = b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        raw.extend_from_slice(&output[body_start..]);
+        let mut decoder = RequestReader::new(&raw[..]);
+        decoder.read_request().unwrap().unwrap();
+        let mut body = Vec::new();
+        decoder.body().read_to_end(&mut body).unwrap();
+        assert_eq!(body, std::fs::read(format!("{}/test.jpg", cfg.root_dir)).unwrap());
+    }
+
+    /// Test malformed requests are answered before connection is closed
+    #[test]
+    fn bad_request_test () {
+        let cfg = test_config();
+        for (raw_req, status) in [
+            ("GET /index.html HTTP/3.0\r\n\r\n", "HTTP/1.1 505 HTTP Version Not Supported"),
+            ("BREW /pot HTTP/1.1\r\n\r\n", "HTTP/1.1 501 Not Implemented"),
+            ("GET /index.html\r\n\r\n", "HTTP/1.1 400 Bad Request"),
+        ].iter() {
+            let raw_req = format!("{}GET / HTTP/1.1\r\n\r\n", raw_req);
+            let mut reader = RequestReader::new(MockStream::new(raw_req.as_bytes()));
+            while handle_request(&mut reader, None, &cfg) {}
+            // request after a malformed one is not handled
+            assert_eq!(status_lines(&reader.get_ref().output), [*status]);
+        }
+    }
+
+    /// Test basic GET method
+    #[test]
+    fn get_test () {
+        let raw_req = 
+        r"GET / HTTP/1.1
+Host: developer.mozilla.org
+Accept-Language: fr
+
+";
+        let raw_resp = resp_from_req_str(raw_req, &test_config());
+        println!("-----\n{}\n-----\n", raw_resp);
+        assert!(raw_resp.starts_with("HTTP/1.1 200 OK"));
+
+        // query string is ignored when looking for file
+        let raw_resp = resp_from_req_str("GET /hello.html?v=2 HTTP/1.1\r\n\r\n", &test_config());
+        assert!(raw_resp.starts_with("HTTP/1.1 200 OK"));
+        let raw_resp = resp_from_req_str("GET /%68ello.html HTTP/1.1\r\n\r\n", &test_config());
+        assert!(raw_resp.starts_with("HTTP/1.1 200 OK"));
+
+        // files outside root dir can not be reached
+        let raw_resp = resp_from_req_str("GET /../Cargo.toml HTTP/1.1\r\n\r\n", &test_config());
+        assert!(raw_resp.starts_with("HTTP/1.1 403 Forbidden"));
+        let raw_resp = resp_from_req_str("GET /..%2fCargo.toml HTTP/1.1\r\n\r\n", &test_config());
+        assert!(raw_resp.starts_with("HTTP/1.1 403 Forbidden"));
+    }
+
+    /// Test basic POST method
+    #[test]
+    fn post_test () {
+        let raw_req = 
+        r"POST /contact_form.php HTTP/1.1
+Host: developer.mozilla.org
+Content-Length: 64
+Content-Type: application/x-www-form-urlencoded
+
+name=Joe%20User&request=Send%20me%20one%20of%20your%20catalogue
+        ";
+        let raw_resp = resp_from_req_str(raw_req, &test_config());
+        println!("-----\n{}\n-----\n", raw_resp);
+        assert!(raw_resp.starts_with("HTTP/1.1 200 OK"));
+    }
+    
+    /// Use POST method to upload a file
+    #[test]
+    fn post_file_test () {
+        let raw_req = 
+    r"POST /data_tobe_send.txt HTTP/1.1
+Host: developer.mozilla.org
+Content-Length: 64
+Content-Type: text/plain
+
+name=Joe%20User&request=Send%20me%20one%20of%20your%20catalogue
+";
+        let cfg = test_upload_config("post_file_test");
+        let raw_resp = resp_from_req_str(raw_req, &cfg);
+        println!("-----\n{}\n-----\n", raw_resp);
+        let written = std::fs::read(format!("{}/data_tobe_send.txt", cfg.root_dir)).unwrap();
+        assert_eq!(written, &b"name=Joe%20User&request=Send%20me%20one%20of%20your%20catalogue\n"[..]);
+    }
+
+    /// HEAD tells the length GET would send, 204 has no Content-Length
+    #[test]
+    fn head_length_test () {
+        let raw_req = "HEAD /hello.html HTTP/1.1\r\n\r\nOPTIONS / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let cfg = Config { chunk: true, ..test_config() };
+        let mut reader = RequestReader::new(MockStream::new(raw_req.as_bytes()));
+        while handle_request(&mut reader, None, &cfg) {}
+        let output = String::from_utf8(reader.get_ref().output.clone()).unwrap();
+        let len = std::fs::metadata(format!("{}/hello.html", cfg.root_dir)).unwrap().len();
+        let (head, options) = output.split_at(output.find("HTTP/1.1 204").unwrap());
+        assert!(head.contains(&format!("Content-Length: {}\r\n", len)));
+        // no body, not even a last chunk
+        assert!(head.ends_with("\r\n\r\n") && !head.contains("chunked"));
+        assert!(!options.contains("Content-Length"));
+
+        // 404 tells the length of the error page GET would send
+        let mut reader = RequestReader::new(MockStream::new(b"HEAD /none HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert!(!handle_request(&mut reader, None, &cfg));
+        let output = String::from_utf8(reader.get_ref().output.clone()).unwrap();
+        let len = std::fs::metadata(format!("{}/error/404.html", cfg.root_dir)).unwrap().len();
+        assert!(output.starts_with("HTTP/1.1 404"));
+        assert!(output.contains(&format!("Content-Length: {}\r\n", len)));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
+
+    /// Rejected request is answered even if client is still sending it
+    #[test]
+    fn reject_large_head_test () {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, test_config());
+        });
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        let mut request = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40 * 1024)).into_bytes();
+        request.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        // server may close before the whole request is sent
+        let _ = client.write_all(&request);
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert_eq!(status_lines(output.as_bytes()), ["HTTP/1.1 431 Request Header Fields Too Large"]);
+        drop(client);
+        server.join().unwrap();
+    }
+
+    /// `port` of an old config file is moved to the first listener
+    #[test]
+    fn upgrade_config_test () {
+        let mut cfg = Config { port: Some(8000), ..Default::default() };
+        cfg.upgrade();
+        assert_eq!(cfg.port, None);
+        assert_eq!(cfg.listeners[0].port, 8000);
+
+        let mut cfg = Config { port: Some(8000), listeners: Vec::new(), ..Default::default() };
+        cfg.upgrade();
+        assert_eq!(cfg.listeners.len(), 1);
+        assert_eq!(cfg.listeners[0].port, 8000);
+        assert!(!cfg.listeners[0].tls);
+    }
+}
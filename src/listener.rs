@@ -0,0 +1,654 @@
+//! Listeners
+//!
+//! rhttp can listen on many ports at the same time, each of them serves
+//! plain HTTP or HTTPS. Connections from all listeners share one thread pool.
+//!
+//! A listener can bind several addresses, IPv4 and IPv6 ones. IPv6 wildcard
+//! `::` also accepts IPv4 clients (dual-stack), unless IPv4 addresses are
+//! bound by the same listener.
+//!
+//! A listener can also be a Unix domain socket, for a local load balancer.
+//! Socket file left by a dead server is removed at startup.
+//!
+//! When all threads are busy and the queue of pool is full, accepting thread
+//! waits, or rejects new connections with 503 if `OverflowPolicy::Reject` is set.
+//!
+//! TLS handshake is done in the pool too, a slow or broken client can not
+//! block the accepting thread.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use openssl::ssl::{ErrorCode, HandshakeError, SslAcceptor, SslStream};
+use socket2::{Domain, Socket, Type};
+
+use super::{Config, ThreadPool, handle_connection, send_response, tls};
+use super::parser::http::{HttpRequest, HttpResponse};
+use super::parser::http::reader::RequestReader;
+use tls::SharedAcceptor;
+
+/// What to do with new connections when the queue of thread pool is full
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Wait for a free slot, clients wait in the backlog of socket
+    Block,
+    /// Answer 503 with `Retry-After` and close connection at once
+    Reject,
+}
+
+/// Socket type of a listener
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerKind {
+    /// TCP port, on `addresses`
+    Tcp,
+    /// Unix domain socket at `path`
+    Unix,
+}
+
+/// Config of a listener
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ListenerConfig {
+    /// TCP or Unix domain socket
+    pub kind: ListenerKind,
+    /// port binding
+    pub port: u32,
+    /// serve HTTPS on this port
+    pub tls: bool,
+    /// IPs or socket addresses to bind, e.g. `0.0.0.0`, `::`, `[::1]:8443`
+    ///
+    /// `port` is used if an address has no port.
+    pub addresses: Vec<String>,
+    /// path of Unix domain socket
+    pub path: String,
+    /// file mode of Unix domain socket in octal, e.g. `660`, empty means umask decides
+    pub mode: String,
+    /// owner of Unix domain socket, user name or uid, empty means unchanged
+    pub owner: String,
+    /// group of Unix domain socket, group name or gid, empty means unchanged
+    pub group: String,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self { Self {
+        kind: ListenerKind::Tcp,
+        port: 7878,
+        tls: false,
+        addresses: vec!["127.0.0.1".to_string()],
+        path: String::new(),
+        mode: String::new(),
+        owner: String::new(),
+        group: String::new(),
+    } }
+}
+
+impl fmt::Display for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ListenerKind::Tcp => write!(f, "port {}", self.port),
+            ListenerKind::Unix => write!(f, "unix:{}", self.path),
+        }
+    }
+}
+
+impl ListenerConfig {
+    /// Parse `addresses`, port of listener is filled in if missing
+    pub fn socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        if self.port > u16::MAX as u32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port {}", self.port)))
+        }
+        self.addresses.iter()
+            .map(|address| {
+                let address = address.trim();
+                if let Ok(addr) = address.parse::<SocketAddr>() {
+                    return Ok(addr)
+                }
+                // IPv6 may be written in brackets without port
+                let ip = address.strip_prefix('[').and_then(|i| i.strip_suffix(']')).unwrap_or(address);
+                match ip.parse::<IpAddr>() {
+                    Ok(ip) => Ok(SocketAddr::new(ip, self.port as u16)),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address {}", address))),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Client connection, plain or TLS
+///
+/// `handle_connection` works on any connection.
+pub trait Connection: Read + Write + Send + 'static {
+    /// Set timeout of waiting for the next request
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+
+    /// Stop sending, client sees the end of the last response
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    /// Subject of verified client certificate, only TLS connection has one
+    fn client_subject(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, dur)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl<S: Connection> Connection for SslStream<S> {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_read_timeout(dur)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.get_ref().shutdown_write()
+    }
+
+    fn client_subject(&self) -> Option<String> {
+        tls::client_subject(self.ssl())
+    }
+}
+
+/// Bind `addr`
+///
+/// IPv6 socket is dual-stack if `only_v6` is false, it is ignored for IPv4.
+fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // restarted server can bind the port at once
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Bind all addresses of `listener_cfg`
+///
+/// IPv6 wildcard is dual-stack unless an IPv4 address with the same port
+/// is also given, which would conflict with it.
+pub fn bind_all(listener_cfg: &ListenerConfig) -> io::Result<Vec<(SocketAddr, TcpListener)>> {
+    let addrs = listener_cfg.socket_addrs()?;
+    addrs.iter()
+        .map(|addr| {
+            let only_v6 = addrs.iter().any(|i| i.is_ipv4() && i.port() == addr.port());
+            bind(*addr, only_v6).map(|listener| (*addr, listener))
+        })
+        .collect()
+}
+
+/// Look up uid of user `name`, which may be a uid already
+fn lookup_uid(name: &str) -> io::Result<u32> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid)
+    }
+    let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid user name"))?;
+    // only called at startup, before any other thread looks up users
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user {}", name)))
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// Look up gid of group `name`, which may be a gid already
+fn lookup_gid(name: &str) -> io::Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid)
+    }
+    let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid group name"))?;
+    // only called at startup, before any other thread looks up groups
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown group {}", name)))
+    }
+    Ok(unsafe { (*group).gr_gid })
+}
+
+/// Remove socket file at `path` if no server is listening on it
+///
+/// Files which are not sockets are never removed.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())))
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is used by another server", path.display()))),
+        Err(_) => {
+            println!("remove stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+    }
+}
+
+/// Bind Unix domain socket of `listener_cfg`, then set its mode and owner
+pub fn bind_unix(listener_cfg: &ListenerConfig) -> io::Result<UnixListener> {
+    let path = Path::new(&listener_cfg.path);
+    if listener_cfg.path.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unix socket without path"))
+    }
+    // parse everything first, socket file is not left if config is wrong
+    let mode = match listener_cfg.mode.as_str() {
+        "" => None,
+        mode => match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o7777 => Some(mode),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid mode {}", mode))),
+        },
+    };
+    let uid = match listener_cfg.owner.as_str() {
+        "" => None,
+        owner => Some(lookup_uid(owner)?),
+    };
+    let gid = match listener_cfg.group.as_str() {
+        "" => None,
+        group => Some(lookup_gid(group)?),
+    };
+
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    let result = mode
+        .map_or(Ok(()), |mode| fs::set_permissions(path, fs::Permissions::from_mode(mode)))
+        .and_then(|_| if uid.is_some() || gid.is_some() { std::os::unix::fs::chown(path, uid, gid) } else { Ok(()) });
+    if let Err(e) = result {
+        let _ = fs::remove_file(path);
+        return Err(e)
+    }
+    Ok(listener)
+}
+
+/// Bind the socket(s) of `listener_cfg`, then accept connections in new threads
+///
+/// Each address has its own accepting thread.
+/// Accepted connections are handled in `pool`.
+/// `acceptor` must be given if the listener serves HTTPS.
+pub fn spawn_listener(listener_cfg: &ListenerConfig, cfg: &Config, pool: Arc<ThreadPool>, acceptor: Option<SharedAcceptor>) -> io::Result<Vec<thread::JoinHandle<()>>> {
+    if listener_cfg.kind == ListenerKind::Unix {
+        if listener_cfg.tls {
+            // TLS is terminated by the load balancer in front
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS is not supported on Unix socket"))
+        }
+        let listener = bind_unix(listener_cfg)?;
+        println!("listening on unix:{} (http)", listener_cfg.path);
+        let cfg = cfg.clone();
+        return Ok(vec![thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_e) => continue, // connection failed
+                };
+                let cfg_cp = cfg.clone();
+                let reply = stream.try_clone().ok();
+                dispatch(&pool, &cfg, reply, move || handle_connection(stream, cfg_cp));
+            }
+        })])
+    }
+    let acceptor = if listener_cfg.tls {
+        match acceptor {
+            Some(acceptor) => Some(acceptor),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS listener without certificate")),
+        }
+    } else {
+        None
+    };
+    // bind everything first, so a wrong address is reported at startup
+    let listeners = bind_all(listener_cfg)?;
+    Ok(listeners.into_iter()
+        .map(|(addr, listener)| {
+            println!("listening on {} ({})", addr, if acceptor.is_some() { "https" } else { "http" });
+            accept_loop(listener, cfg.clone(), pool.clone(), acceptor.clone())
+        })
+        .collect())
+}
+
+/// Accept connections of `listener` in a new thread
+fn accept_loop(listener: TcpListener, cfg: Config, pool: Arc<ThreadPool>, acceptor: Option<SharedAcceptor>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        // when new TCP request incomes, handle_connection
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_e) => continue, // connection failed
+            };
+            let cfg_cp = cfg.clone();
+            match &acceptor {
+                // certificates may be reloaded, always use the current acceptor
+                Some(acceptor) => {
+                    let acceptor = acceptor.current();
+                    // 503 can not be sent before handshake, connection is just closed
+                    dispatch(&pool, &cfg, None::<TcpStream>, move || {
+                        if let Some(stream) = accept_tls(stream, &acceptor, &cfg_cp) {
+                            handle_connection(stream, cfg_cp)
+                        }
+                    })
+                }
+                None => {
+                    let reply = stream.try_clone().ok();
+                    dispatch(&pool, &cfg, reply, move || handle_connection(stream, cfg_cp))
+                }
+            }
+        }
+    })
+}
+
+/// Run `job` in `pool`, or reject it if the queue is full and `cfg.overflow` says so
+///
+/// `reply` is a clone of the connection of `job`, 503 is sent through it on rejection.
+pub fn dispatch<W, F>(pool: &ThreadPool, cfg: &Config, reply: Option<W>, job: F)
+where
+    W: Write,
+    F: FnOnce() + Send + 'static,
+{
+    match cfg.overflow {
+        OverflowPolicy::Block => pool.execute(job),
+        OverflowPolicy::Reject => {
+            if pool.try_execute(job) {
+                return
+            }
+            println!("all {} threads are busy, reject connection", pool.size());
+            if let Some(mut reply) = reply {
+                send_response(&mut reply, HttpResponse::error_503(cfg.retry_after), false, false, cfg);
+            }
+        }
+    }
+}
+
+/// Address of a client in logs
+pub fn peer_name(addr: io::Result<SocketAddr>) -> String {
+    match addr {
+        Ok(addr) => addr.to_string(),
+        Err(_) => "unknown peer".to_string(),
+    }
+}
+
+/// Connection on a HTTPS port, told by its first byte
+#[derive(Debug, PartialEq)]
+pub enum FirstByte {
+    /// TLS record of handshake starts with 0x16
+    Tls,
+    /// Plain HTTP starts with a method name
+    PlainHttp,
+    /// Client closed TCP link, or it is broken
+    Closed,
+}
+
+/// Check the result of peeking the first byte of a connection
+///
+/// Plain HTTP and broken links are logged as failed handshakes.
+pub fn check_first_byte(peeked: io::Result<usize>, first: u8, peer: &str) -> FirstByte {
+    match peeked {
+        Ok(0) => FirstByte::Closed,
+        Ok(_) if first.is_ascii_alphabetic() => {
+            println!("TLS handshake with {} failed: plain HTTP request on HTTPS port", peer);
+            FirstByte::PlainHttp
+        }
+        Ok(_) => FirstByte::Tls,
+        Err(e) => {
+            println!("TLS handshake with {} failed: {}", peer, e);
+            FirstByte::Closed
+        }
+    }
+}
+
+/// Wait until TLS handshake on non-blocking `stream` can go on, at most `timeout`
+///
+/// Errors are left to the next handshake step.
+fn wait_handshake(stream: &TcpStream, want_read: bool, timeout: Option<Duration>) -> io::Result<()> {
+    if !want_read {
+        // send buffer is full, which rarely happens in handshake
+        thread::sleep(timeout.map_or(WRITE_RETRY, |timeout| timeout.min(WRITE_RETRY)));
+        return Ok(())
+    }
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(timeout)?;
+    let _ = stream.peek(&mut [0u8; 1]);
+    stream.set_nonblocking(true)
+}
+
+/// Pause before retrying a handshake blocked on writing
+const WRITE_RETRY: Duration = Duration::from_millis(10);
+
+/// Do TLS handshake on `stream`, failures are logged with the peer address
+///
+/// `handshake_timeout` limits the whole handshake, a client sending bytes
+/// slowly can not extend it.
+///
+/// Return `None` if handshake failed, or a plain HTTP request is answered.
+fn accept_tls(stream: TcpStream, acceptor: &SslAcceptor, cfg: &Config) -> Option<SslStream<TcpStream>> {
+    let peer = peer_name(stream.peer_addr());
+    let timeout = match cfg.tls.handshake_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    if stream.set_read_timeout(timeout).is_err() || stream.set_write_timeout(timeout).is_err() {
+        return None
+    }
+
+    let mut first = [0u8; 1];
+    match check_first_byte(stream.peek(&mut first), first[0], &peer) {
+        FirstByte::Tls => (),
+        FirstByte::PlainHttp => {
+            if cfg.tls.redirect_plain_http {
+                redirect_to_https(stream, cfg);
+            }
+            return None
+        }
+        FirstByte::Closed => return None,
+    }
+
+    // each step returns when socket would block, so deadline is checked between reads
+    if stream.set_nonblocking(true).is_err() {
+        return None
+    }
+    let mut result = acceptor.accept(stream);
+    loop {
+        match result {
+            Ok(stream) => {
+                // only handshake is limited, the first request waits like a plain HTTP one
+                let tcp = stream.get_ref();
+                if tcp.set_nonblocking(false).is_err() || tcp.set_read_timeout(None).is_err() || tcp.set_write_timeout(None).is_err() {
+                    return None
+                }
+                return Some(stream)
+            }
+            Err(HandshakeError::WouldBlock(mid)) => {
+                let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                if remaining == Some(Duration::from_secs(0)) {
+                    println!("TLS handshake with {} failed: timed out after {} secs", peer, cfg.tls.handshake_timeout);
+                    return None
+                }
+                if wait_handshake(mid.get_ref(), mid.error().code() == ErrorCode::WANT_READ, remaining).is_err() {
+                    return None
+                }
+                result = mid.handshake();
+            }
+            Err(HandshakeError::Failure(mid)) => {
+                println!("TLS handshake with {} failed: {}", peer, mid.error());
+                return None
+            }
+            Err(HandshakeError::SetupFailure(e)) => {
+                println!("TLS handshake with {} failed: {}", peer, e);
+                return None
+            }
+        }
+    }
+}
+
+/// Answer a plain HTTP request with a redirect to the same URL in https
+///
+/// Host header already has the port of HTTPS listener.
+fn redirect_to_https(stream: TcpStream, cfg: &Config) {
+    let mut reader = RequestReader::new(stream);
+    let head = match reader.read_request() {
+        Ok(Some(head)) => head,
+        _ => return,
+    };
+    send_response(reader.get_mut(), redirect_response(&head), false, false, cfg);
+}
+
+/// Redirect of a plain HTTP request with `head` to the same URL in https
+pub fn redirect_response<'t>(head: &[u8]) -> HttpResponse<'t> {
+    match HttpRequest::from_parts(head, Box::new(io::empty())) {
+        Ok(request) => match request.headers.host() {
+            Some(host) => HttpResponse::redirect_301(&format!("https://{}{}", host, request.url)),
+            None => HttpResponse::error_400(),
+        },
+        Err(e) => HttpResponse::from_parse_error(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// Run `accept_tls` on the server side of a new TCP link
+    fn accept_with(cfg: Config) -> (TcpStream, thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let acceptor = tls::build_dev_acceptor(&cfg.tls).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            accept_tls(stream, &acceptor, &cfg).is_some()
+        });
+        (TcpStream::connect(addr).unwrap(), handle)
+    }
+
+    #[test]
+    fn parse_addresses() {
+        let listener_cfg = ListenerConfig {
+            port: 8080,
+            addresses: vec!["0.0.0.0".to_string(), "::".to_string(), "[::1]".to_string(), "[::1]:8443".to_string(), "10.0.0.1:80".to_string()],
+            ..Default::default()
+        };
+        let addrs: Vec<String> = listener_cfg.socket_addrs().unwrap().iter().map(|i| i.to_string()).collect();
+        assert_eq!(addrs, ["0.0.0.0:8080", "[::]:8080", "[::1]:8080", "[::1]:8443", "10.0.0.1:80"]);
+        for address in &["localhost", "1.2.3", "[::1"] {
+            let listener_cfg = ListenerConfig { addresses: vec![address.to_string()], ..Default::default() };
+            assert!(listener_cfg.socket_addrs().is_err(), "{}", address);
+        }
+        let listener_cfg = ListenerConfig { port: 70000, ..Default::default() };
+        assert!(listener_cfg.socket_addrs().is_err());
+    }
+
+    #[test]
+    fn bind_dual_stack() {
+        // IPv6 may be disabled on test machine
+        if TcpListener::bind("[::1]:0").is_err() {
+            return
+        }
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener_cfg = ListenerConfig { port: port as u32, addresses: vec!["::".to_string()], ..Default::default() };
+        let listeners = bind_all(&listener_cfg).unwrap();
+        // IPv4 client reaches the IPv6 wildcard socket
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+        assert!(TcpStream::connect(("::1", port)).is_ok());
+        drop(listeners);
+
+        // IPv4 and IPv6 wildcard side by side
+        let listener_cfg = ListenerConfig { port: port as u32, addresses: vec!["0.0.0.0".to_string(), "::".to_string()], ..Default::default() };
+        assert_eq!(bind_all(&listener_cfg).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unix_socket() {
+        let path = std::env::temp_dir().join(format!("rhttp_test_{}.sock", std::process::id()));
+        let listener_cfg = ListenerConfig {
+            kind: ListenerKind::Unix,
+            path: path.to_str().unwrap().to_string(),
+            mode: "600".to_string(),
+            ..Default::default()
+        };
+        // socket file of a dead server
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&listener_cfg).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o600);
+        // socket used by a running server is kept
+        assert_eq!(bind_unix(&listener_cfg).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+
+        let cfg = crate::tests::test_config();
+        spawn_listener(&listener_cfg, &cfg, Arc::new(ThreadPool::new(1, 1, 1, None)), None).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"GET /hello.html HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        fs::remove_file(&path).unwrap();
+
+        // regular file is never removed
+        fs::write(&path, "").unwrap();
+        assert!(bind_unix(&listener_cfg).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn plain_http_redirect() {
+        let mut cfg = Config::default();
+        cfg.tls.redirect_plain_http = true;
+        let (mut client, handle) = accept_with(cfg);
+        client.write_all(b"GET /a?b=1 HTTP/1.1\r\nHost: localhost:7879\r\n\r\n").unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(!handle.join().unwrap());
+        assert!(output.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(output.contains("Location: https://localhost:7879/a?b=1\r\n"));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let mut cfg = Config::default();
+        cfg.tls.handshake_timeout = 1;
+        let (mut client, handle) = accept_with(cfg);
+        // send part of a TLS record, then nothing
+        client.write_all(&[0x16, 0x03]).unwrap();
+        assert!(!handle.join().unwrap());
+
+        // handshake is limited as a whole, not per read
+        let mut cfg = Config::default();
+        cfg.tls.handshake_timeout = 1;
+        let (mut client, handle) = accept_with(cfg);
+        let started = Instant::now();
+        // header of a long handshake record, its content trickles in
+        client.write_all(&[0x16, 0x03, 0x01, 0x02, 0x00]).unwrap();
+        while !handle.is_finished() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(300));
+            let _ = client.write_all(&[0]);
+        }
+        assert!(!handle.join().unwrap());
+        assert!(started.elapsed() < Duration::from_millis(2500));
+    }
+}
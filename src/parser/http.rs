@@ -3,12 +3,24 @@
 //! Parse HTTP request, generate HTTP response.
 
 use std::fmt;
-use std::collections::BTreeMap;
+use std::io;
+use std::convert::TryFrom;
+use std::io::prelude::*;
 
 use super::super::BUFFER_SIZE;
 use super::super::Config;
 
 pub mod method;
+pub mod reader;
+pub mod header;
+pub mod error;
+pub mod uri;
+pub mod body;
+
+pub use header::HeaderMap;
+pub use error::{ParseError, MAX_URI_LENGTH};
+pub use uri::Query;
+pub use body::Body;
 
 // Parse HTTP Request
 
@@ -19,7 +31,6 @@ pub enum HttpRequestMethod {
     HEAD,
     PUT,
     OPTIONS,
-    ILLEGAL, // -> Ignored
 }
 
 /// Parsed HTTP request
@@ -28,15 +39,27 @@ pub enum HttpRequestMethod {
 /// 
 /// ref: https://github.com/lennart-bot/lhi/blob/master/src/server/request.rs
 /// 
-/// It provides the idea to use reference & BTreeMap to track http head entries
-#[derive(Debug)]
+/// It provides the idea to use reference to track http head entries
+/// 
+/// Only the head is parsed (as ASCII), body is kept as untouched bytes,
+/// so binary payload (images, zips) will not be damaged.
 pub struct HttpRequest<'t> {
     pub method: HttpRequestMethod,
     pub url: &'t str, // use reference to avoid copying
+    /// Percent-decoded path of `url`, query and fragment are removed
+    pub path: String,
+    /// Parsed query string of `url`
+    pub query: Query,
     pub version: &'t str, // use reference to avoid copying
-    pub headers: BTreeMap<String, &'t str>, // Other fields in head, if necessary
-    pub body: std::str::Lines<'t>,
+    pub headers: HeaderMap, // Other fields in head, if necessary
+    /// Raw request body stream, binary safe
+    /// 
+    /// Body is not read into memory, handlers can copy it to its destination directly.
+    pub body: Box<dyn Read + 't>,
+    /// Size of request head
     pub size: usize,
+    /// Subject of verified TLS client certificate, `None` if client sent no certificate
+    pub client_subject: Option<String>,
 }
 
 impl fmt::Display for HttpRequest<'_> {
@@ -47,86 +70,176 @@ impl fmt::Display for HttpRequest<'_> {
             HttpRequestMethod::HEAD    => "HEAD",
             HttpRequestMethod::PUT     => "PUT",
             HttpRequestMethod::OPTIONS => "OPTIONS",
-            _ => "ILLEGAL"
         };
         write!(f, "HttpRequest:\nmethod {}\nurl {}\nversion {}\nheaders {:#?}", req_type, self.url, self.version, self.headers)
     }
 }
 
-impl<'t> From<&'t str> for HttpRequest<'t> {
-    /// Transform raw http req string to HttpRequest
+/// Find the end of http head in raw bytes
+/// 
+/// Head ends with an empty line (`\r\n\r\n`, bare `\n\n` is also accepted).
+/// 
+/// Return `Some((head_len, body_start))` if an empty line is found.
+pub fn find_head_end(input: &[u8]) -> Option<(usize, usize)> {
+    let mut pos = 0;
+    while let Some(offset) = input[pos..].iter().position(|&b| b == b'\n') {
+        let line_end = pos + offset + 1;
+        if input[line_end..].starts_with(b"\r\n") {
+            return Some((line_end, line_end + 2))
+        }
+        if input[line_end..].starts_with(b"\n") {
+            return Some((line_end, line_end + 1))
+        }
+        pos = line_end;
+    }
+    None
+}
+
+impl<'t> TryFrom<&'t [u8]> for HttpRequest<'t> {
+    type Error = ParseError;
+
+    /// Transform raw http req bytes to HttpRequest
     /// 
-    /// Use rust's "from/into" style
+    /// Use rust's "try_from/try_into" style
     /// ```
-    /// let hr = HttpRequest::from(raw_head);
+    /// # use std::convert::TryFrom;
+    /// # use rhttp::{HttpRequest, ParseError};
+    /// # let raw_bytes = &b"GET / HTTP/1.1\r\n\r\n"[..];
+    /// let hr = HttpRequest::try_from(raw_bytes)?;
+    /// # Ok::<(), ParseError>(())
     /// ```
+    /// 
+    /// Bytes after the empty line are kept as body as is,
+    /// use `RequestReader` to deal with body framing.
+    fn try_from (input: &'t [u8]) -> Result<Self, ParseError> {
+        match find_head_end(input) {
+            Some((head_len, body_start)) => HttpRequest::from_parts(&input[..head_len], Box::new(&input[body_start..])),
+            None => HttpRequest::from_parts(input, Box::new(io::empty())),
+        }
+    }
+}
 
-    fn from (input: &'t str) -> Self {
-        let mut lines = input.lines();
+/// Check if `s` is a token, which is used as method and header name
+/// 
+/// ref: https://tools.ietf.org/html/rfc7230#section-3.2.6
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+impl<'t> HttpRequest<'t> {
+    /// Build HttpRequest from raw head and body stream
+    /// 
+    /// Head must be ASCII, body is kept as untouched bytes.
+    /// 
+    /// Body framing headers are checked here, so `RequestReader` can trust
+    /// `Content-Length` and `Transfer-Encoding` of a parsed request.
+    pub fn from_parts(head: &'t [u8], body: Box<dyn Read + 't>) -> Result<Self, ParseError> {
+        if head.len() > BUFFER_SIZE {
+            return Err(ParseError::HeaderTooLarge)
+        }
+        if !head.is_ascii() {
+            return Err(ParseError::MalformedHeader)
+        }
+        let head_str = match std::str::from_utf8(head) {
+            Ok(s) => s,
+            Err(_) => return Err(ParseError::MalformedHeader),
+        };
+        let mut lines = head_str.lines();
         
         let start_line = match lines.next() {
             Some(line) => line,
-            None => return HttpRequest::invalid_request(),
+            None => return Err(ParseError::MalformedRequestLine),
         };
-        let mut start_line_splited = start_line.split(" ");
+        let mut start_line_splited = start_line.split(' ');
         
-        let method = match start_line_splited.next() {
-            Some(raw_method) => match raw_method {
-                "GET"     => HttpRequestMethod::GET,
-                "POST"    => HttpRequestMethod::POST,
-                "HEAD"    => HttpRequestMethod::HEAD,
-                "PUT"     => HttpRequestMethod::PUT,
-                "OPTIONS" => HttpRequestMethod::OPTIONS,
-                _ => return HttpRequest::invalid_request(),
-            },
-            None => return HttpRequest::invalid_request(),
+        let (raw_method, url, version) = match (start_line_splited.next(), start_line_splited.next(), start_line_splited.next(), start_line_splited.next()) {
+            (Some(raw_method), Some(raw_url), Some(raw_version), None) => (raw_method, raw_url, raw_version),
+            _ => return Err(ParseError::MalformedRequestLine),
         };
         
-        let url = match start_line_splited.next() {
-            Some(raw_url) => raw_url,
-            None => return HttpRequest::invalid_request(),
+        let method = match raw_method {
+            "GET"     => HttpRequestMethod::GET,
+            "POST"    => HttpRequestMethod::POST,
+            "HEAD"    => HttpRequestMethod::HEAD,
+            "PUT"     => HttpRequestMethod::PUT,
+            "OPTIONS" => HttpRequestMethod::OPTIONS,
+            x if is_token(x) => return Err(ParseError::UnsupportedMethod),
+            _ => return Err(ParseError::MalformedRequestLine),
         };
         
-        let version = match start_line_splited.next() {
-            Some(raw_version) => raw_version,
-            None => return HttpRequest::invalid_request(),
-        };
+        if url.is_empty() {
+            return Err(ParseError::MalformedRequestLine)
+        }
+        if url.len() > MAX_URI_LENGTH {
+            return Err(ParseError::UriTooLong)
+        }
+        
+        match version {
+            "HTTP/1.1" | "HTTP/1.0" => {},
+            x if x.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::MalformedRequestLine),
+        }
+
+        let (path, query) = uri::parse_target(url)?;
 
-        let mut headers = BTreeMap::<String, &'t str>::new();
+        let mut headers = HeaderMap::new();
 
-        // check line by line, do not stop until we can not find valid "k: v" pair
-        loop {
-            let mut line_splited = lines.next().unwrap_or("").split(":");
+        // check line by line, every line must be a valid "k: v" pair
+        for line in lines {
+            let mut line_splited = line.splitn(2, ':');
             match (line_splited.next(), line_splited.next()) {
-                (Some(k), Some(v)) => {
-                    headers.insert(k.trim().to_string(), v.trim());
+                (Some(k), Some(v)) if is_token(k) => {
+                    headers.append(k, v.trim());
                 },
-                _ => break
+                _ => return Err(ParseError::MalformedHeader)
             }
         }
 
-        // println!("headers BT: {:#?}", headers);
-        // println!("http head parse result: {}", result);
-        Self {
-            method: method,
-            url: url,
-            version: version,
-            headers: headers,
-            body: lines,
-            size: input.chars().count(),
+        // check body framing
+        // ref: https://tools.ietf.org/html/rfc7230#section-3.3.3
+        if let Some(length) = headers.get("Content-Length") {
+            if headers.content_length().is_none() {
+                return Err(ParseError::InvalidContentLength)
+            }
+            if headers.get_all("Content-Length").any(|i| i != length) {
+                return Err(ParseError::ConflictingFraming)
+            }
         }
+        if let Some(coding) = headers.get("Transfer-Encoding") {
+            if !coding.eq_ignore_ascii_case("chunked") || headers.get_all("Transfer-Encoding").count() > 1 {
+                return Err(ParseError::UnsupportedTransferEncoding)
+            }
+            if headers.contains_key("Content-Length") {
+                return Err(ParseError::ConflictingFraming)
+            }
+        }
+
+        Ok(Self {
+            method,
+            url,
+            path,
+            query,
+            version,
+            headers,
+            body,
+            size: head.len(),
+            client_subject: None,
+        })
     }
 }
 
 impl HttpRequest<'_> {
-    fn invalid_request() -> Self {
-        HttpRequest {
-            method: HttpRequestMethod::ILLEGAL,
-            url: "",
-            version: "",
-            headers: BTreeMap::new(),
-            body: "".lines(),
-            size: 0,
+    /// Check if connection should be kept alive after this request
+    /// 
+    /// HTTP/1.1 keeps connection alive unless `Connection: close` is assigned,
+    /// HTTP/1.0 closes connection unless `Connection: keep-alive` is assigned.
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_option("Connection", "close") {
+            false
+        } else if self.headers.has_option("Connection", "keep-alive") {
+            true
+        } else {
+            self.version == "HTTP/1.1"
         }
     }
 }
@@ -137,7 +250,7 @@ impl HttpRequest<'_> {
 /// 
 /// ref: https://github.com/lennart-bot/lhi/blob/master/src/server/request.rs
 /// 
-/// It provides the idea to use reference & BTreeMap to track http head entries
+/// It provides the idea to track http head entries in a map
 ///
 /// ref: https://developer.mozilla.org/zh-CN/docs/Web/HTTP/Messages
 /// 
@@ -147,11 +260,12 @@ pub struct HttpResponse<'t> {
     
     pub status_code: u32,
     pub status_text: &'t str,
-    /// <String, String> instead of <String, &str> 
-    pub headers: BTreeMap<String, String>, 
-    /// If body is encoded in UTF-8, return an UTF-8 String
-    /// Otherwise, body is generated by raw file or other functions
-    pub body: Option<String>, 
+    /// Owned header values, case-insensitive
+    pub headers: HeaderMap, 
+    /// Binary safe body, written to stream as is
+    pub body: Body,
+    /// Trailer fields, only sent if body is chunked
+    pub trailers: HeaderMap,
 }
 
 impl fmt::Display for HttpResponse<'_> {
@@ -166,17 +280,29 @@ impl HttpResponse<'_> {
         Self {
             status_code: 400,
             status_text: "Bad Request",
-            headers: BTreeMap::<String, String>::new(),
-            body: Some("".to_string()),
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    pub fn error_403() -> Self {
+        Self {
+            status_code: 403,
+            status_text: "Forbidden",
+            headers: HeaderMap::new(),
+            body: "403 Forbidden".into(),
+            trailers: HeaderMap::new(),
         }
     }
 
-    pub fn _error_404() -> Self {
+    pub fn error_404() -> Self {
         Self {
             status_code: 404,
             status_text: "Not Found",
-            headers: BTreeMap::<String, String>::new(),
-            body: Some("404 Not Found".to_string()),
+            headers: HeaderMap::new(),
+            body: "404 Not Found".into(),
+            trailers: HeaderMap::new(),
         }
     }
 
@@ -184,8 +310,22 @@ impl HttpResponse<'_> {
         Self {
             status_code: 405,
             status_text: "Method Not Allowed",
-            headers: BTreeMap::<String, String>::new(),
-            body: Some("".to_string()),
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    pub fn error_413() -> Self {
+        let mut headers = HeaderMap::new();
+        // the rest of body is not consumed, connection can not be reused
+        headers.insert("Connection".to_string(), "close".to_string());
+        Self {
+            status_code: 413,
+            status_text: "Payload Too Large",
+            headers,
+            body: Body::Empty,
+            trailers: HeaderMap::new(),
         }
     }
 
@@ -193,8 +333,9 @@ impl HttpResponse<'_> {
         Self {
             status_code: 500,
             status_text: "Internal Server Error",
-            headers: BTreeMap::<String, String>::new(),
-            body: Some("Undefined Interal Error Resp Body".to_string()),
+            headers: HeaderMap::new(),
+            body: "Undefined Interal Error Resp Body".into(),
+            trailers: HeaderMap::new(),
         }
     }
 
@@ -202,8 +343,74 @@ impl HttpResponse<'_> {
         Self {
             status_code: 507,
             status_text: "Insufficient Storage",
-            headers: BTreeMap::<String, String>::new(),
-            body: Some("".to_string()),
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    /// Tell client the server is overloaded, try again after `retry_after` secs
+    pub fn error_503(retry_after: u64) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", retry_after.to_string());
+        Self {
+            status_code: 503,
+            status_text: "Service Unavailable",
+            headers,
+            body: "503 Service Unavailable".into(),
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    /// Tell client the resource is moved to `location` permanently
+    pub fn redirect_301(location: &str) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert("Location", location);
+        Self {
+            status_code: 301,
+            status_text: "Moved Permanently",
+            headers,
+            body: format!("use {}", location).into(),
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    /// Generate error HttpResponse from a request parse error
+    /// 
+    /// Connection will be closed, for the rest of request can not be trusted.
+    pub fn from_parse_error(e: &ParseError) -> Self {
+        let (status_code, status_text) = e.status();
+        let mut headers = HeaderMap::new();
+        headers.insert("Connection", "close");
+        Self {
+            status_code,
+            status_text,
+            headers,
+            body: format!("{} {}: {}", status_code, status_text, e).into(),
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    /// Generate error HttpResponse from a path which can not be resolved
+    pub fn from_path_error(e: &method::utils::PathError) -> Self {
+        match e {
+            method::utils::PathError::Forbidden => HttpResponse::error_403(),
+            method::utils::PathError::NotFound => HttpResponse::error_404(),
+        }
+    }
+
+    /// Generate error HttpResponse from an error raised by reading request body
+    /// 
+    /// * Body larger than `max_body_size`: 413
+    /// * Broken body framing: 400
+    /// * Others: 500
+    pub fn from_body_error(e: &io::Error) -> Self {
+        if reader::is_payload_too_large(e) {
+            HttpResponse::error_413()
+        } else if let Some(parse_error) = ParseError::from_io(e) {
+            HttpResponse::from_parse_error(parse_error)
+        } else {
+            HttpResponse::error_500()
         }
     }
 
@@ -212,18 +419,13 @@ impl HttpResponse<'_> {
     /// * Return Ok(HttpResponse) if a response is needed
     /// * Return None if no response is required
     pub fn new(request: &mut HttpRequest, cfg: &Config) -> Option<Self> {
-        let mut headers = BTreeMap::<String, String>::new();
+        let mut headers = HeaderMap::new();
         
         // Response Headers
         headers.insert("Server".to_string(), "rhttp".to_string());
         
         // HttpRequest match
         match request.method {
-            HttpRequestMethod::ILLEGAL => {
-                println!("Ignored illegal request");
-                return None
-            }
-
             HttpRequestMethod::GET => {
                 method::generate_get_response(request, headers, cfg)
             }
@@ -246,29 +448,13 @@ impl HttpResponse<'_> {
         }
     }
 
-    /// Check if raw file need to be send via http directly
-    pub fn need_send_raw_file(&self) -> bool {
-        self.body == None
-    }
-
     /// Generate real HTTP response from HttpResponse
     /// 
-    /// Outdated
+    /// Outdated, only body in memory is included
     pub fn generate_string(&self) -> String {
-        let status_line = format!("HTTP/1.1 {} {}\n", self.status_code, self.status_text);
-        let mut headers_str = String::new();
-        for (k, v) in &self.headers {
-            headers_str.push_str(&format!("{}: {}\n", k, v));
-        }
-        headers_str.push('\n'); // add a space line
         match &self.body {
-            Some(body_str) => {
-                String::from(format!("{}{}{}", status_line, headers_str, body_str))
-            },
-            None => {
-                String::from(format!("{}{}", status_line, headers_str))
-                // read file later
-            }
+            Body::Bytes(bytes) => format!("{}{}", self.generate_head_string(), String::from_utf8_lossy(bytes)),
+            _ => self.generate_head_string(),
         }
     }
 
@@ -276,14 +462,17 @@ impl HttpResponse<'_> {
     /// 
     /// Body part can be Vec<u8>, not compatible with String (UTF-8 only),
     /// so we need to deal with body part differently
+    /// 
+    /// Lines end with CRLF.
+    /// ref: https://tools.ietf.org/html/rfc7230#section-3
     pub fn generate_head_string(&self) -> String {
-        let status_line = format!("HTTP/1.1 {} {}\n", self.status_code, self.status_text);
+        let status_line = format!("HTTP/1.1 {} {}\r\n", self.status_code, self.status_text);
         let mut headers_str = String::new();
         for (k, v) in &self.headers {
-            headers_str.push_str(&format!("{}: {}\n", k, v));
+            headers_str.push_str(&format!("{}: {}\r\n", k, v));
         }
-        headers_str.push('\n'); // add a space line
-        String::from(format!("{}{}", status_line, headers_str))
+        headers_str.push_str("\r\n"); // add a space line
+        format!("{}{}", status_line, headers_str)
     }
 }
 
@@ -308,7 +497,43 @@ Accept-Encoding: gzip, deflate, br
 Accept-Language: zh-CN,zh;q=0.9,en;q=0.8,en-GB;q=0.7,en-US;q=0.6
 Cookie: zentaosid=g9uhstb2uthp5budvqbh6j3d0u
 ";
-        let request = HttpRequest::from(raw_get);
+        let request = HttpRequest::try_from(raw_get.as_bytes()).unwrap();
         assert_eq!(request.method, HttpRequestMethod::GET);
+        assert_eq!(request.headers.get("host"), Some("127.0.0.1:7878"));
+
+        let request = HttpRequest::try_from(&b"GET /my%20page.html?lang=zh&v=2 HTTP/1.1\r\n\r\n"[..]).unwrap();
+        assert_eq!(request.url, "/my%20page.html?lang=zh&v=2");
+        assert_eq!(request.path, "/my page.html");
+        assert_eq!(request.query.get("lang"), Some("zh"));
+    }
+
+    #[test]
+    fn parse_binary_body() {
+        let mut raw_put = b"PUT /test.bin HTTP/1.1\r\nContent-Length: 6\r\n\r\n".to_vec();
+        let payload = [0xffu8, 0xd8, 0x00, b'\r', b'\n', 0x80];
+        raw_put.extend_from_slice(&payload);
+        let mut request = HttpRequest::try_from(&raw_put[..]).unwrap();
+        assert_eq!(request.method, HttpRequestMethod::PUT);
+        assert_eq!(request.headers.content_length(), Some(6));
+        let mut body = Vec::new();
+        request.body.read_to_end(&mut body).unwrap();
+        assert_eq!(body, &payload[..]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reject_bad_request() {
+        let parse = |raw: &str| HttpRequest::try_from(raw.as_bytes()).err();
+        assert_eq!(parse("GET /\r\n\r\n"), Some(ParseError::MalformedRequestLine));
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost\r\n\r\n"), Some(ParseError::MalformedHeader));
+        assert_eq!(parse("BREW / HTTP/1.1\r\n\r\n"), Some(ParseError::UnsupportedMethod));
+        assert_eq!(parse("GET / HTTP/2.0\r\n\r\n"), Some(ParseError::UnsupportedVersion));
+        assert_eq!(parse(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_URI_LENGTH))), Some(ParseError::UriTooLong));
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: x\r\n\r\n"), Some(ParseError::InvalidContentLength));
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: +5\r\n\r\n"), Some(ParseError::InvalidContentLength));
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: -0\r\n\r\n"), Some(ParseError::InvalidContentLength));
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"), Some(ParseError::ConflictingFraming));
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n"), Some(ParseError::ConflictingFraming));
+        assert_eq!(parse("PUT / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), Some(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(parse("GET /%zz HTTP/1.1\r\n\r\n"), Some(ParseError::MalformedUri));
+    }
+}
@@ -0,0 +1,101 @@
+//! HTTP response body
+//!
+//! Body is kept as bytes, an opened file or a reader, it is never
+//! converted to `String`, so binary files are sent byte-for-byte.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+
+/// Response body returned by handlers, written out by `send_response`
+#[derive(Default)]
+pub enum Body {
+    /// No body
+    #[default]
+    Empty,
+    /// Body in memory
+    Bytes(Vec<u8>),
+    /// Opened file with its length, file is copied to stream directly
+    File(fs::File, u64),
+    /// Body of unknown length, stream is read until EOF
+    Stream(Box<dyn Read>),
+    /// Body which is not sent, only its length is told, e.g. response to HEAD
+    Omitted(u64),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File(_, len) => write!(f, "File({} bytes)", len),
+            Body::Stream(_) => write!(f, "Stream"),
+            Body::Omitted(len) => write!(f, "Omitted({} bytes)", len),
+        }
+    }
+}
+
+impl Body {
+    /// Open file `filename` as body
+    pub fn from_file<P: AsRef<std::path::Path>>(filename: P) -> io::Result<Self> {
+        let file = fs::File::open(filename)?;
+        let len = file.metadata()?.len();
+        Ok(Body::File(file, len))
+    }
+
+    /// Length of body, `None` if it is unknown before sending
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(_, len) => Some(*len),
+            Body::Stream(_) => None,
+            Body::Omitted(len) => Some(*len),
+        }
+    }
+
+    /// No byte is written, though `len` of an omitted body is not 0
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Body::Omitted(_)) || self.len() == Some(0)
+    }
+
+    /// Write the whole body to `stream`
+    ///
+    /// Return the number of bytes written.
+    /// File body is cut at the length it had when opened.
+    pub fn write_to<W: Write + ?Sized>(self, stream: &mut W) -> io::Result<u64> {
+        match self {
+            Body::Empty => Ok(0),
+            Body::Bytes(bytes) => stream.write_all(&bytes).map(|_| bytes.len() as u64),
+            Body::File(file, len) => {
+                let size = io::copy(&mut file.take(len), stream)?;
+                if size < len {
+                    // file was truncated after Content-Length is sent
+                    return Err(io::ErrorKind::UnexpectedEof.into())
+                }
+                Ok(size)
+            }
+            Body::Stream(mut reader) => io::copy(&mut reader, stream),
+            Body::Omitted(_) => Ok(0),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        Body::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(s: &str) -> Self {
+        Body::Bytes(s.as_bytes().to_vec())
+    }
+}
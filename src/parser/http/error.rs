@@ -0,0 +1,85 @@
+//! HTTP request parse errors
+//!
+//! Every malformed request is rejected with a proper status code
+//! instead of being dropped silently.
+
+use std::fmt;
+use std::io;
+
+/// Max length of request-target
+pub const MAX_URI_LENGTH: usize = 8192;
+
+/// Reason why a request can not be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Request line is not `method SP request-target SP HTTP-version`
+    MalformedRequestLine,
+    /// Header line is not `field-name ":" field-value`
+    MalformedHeader,
+    /// Request head is larger than `BUFFER_SIZE`
+    HeaderTooLarge,
+    /// Request-target is longer than `MAX_URI_LENGTH`
+    UriTooLong,
+    /// Request-target is not a path, or has invalid `%` escape
+    MalformedUri,
+    /// Method is valid but not supported by rhttp
+    UnsupportedMethod,
+    /// HTTP version other than HTTP/1.0 and HTTP/1.1
+    UnsupportedVersion,
+    /// `Content-Length` is not a valid number
+    InvalidContentLength,
+    /// Transfer coding other than chunked
+    UnsupportedTransferEncoding,
+    /// Both `Content-Length` and `Transfer-Encoding`, or different `Content-Length`s
+    ConflictingFraming,
+    /// Chunked body does not follow chunk framing
+    BadChunk,
+}
+
+impl ParseError {
+    /// Status code and status text of the response to this error
+    pub fn status(&self) -> (u32, &'static str) {
+        match self {
+            ParseError::HeaderTooLarge => (431, "Request Header Fields Too Large"),
+            ParseError::UriTooLong => (414, "URI Too Long"),
+            ParseError::UnsupportedMethod => (501, "Not Implemented"),
+            ParseError::UnsupportedTransferEncoding => (501, "Not Implemented"),
+            ParseError::UnsupportedVersion => (505, "HTTP Version Not Supported"),
+            _ => (400, "Bad Request"),
+        }
+    }
+
+    /// Get the ParseError carried by an io error, if any
+    ///
+    /// ParseError is carried by io error when it is raised inside `Read`.
+    pub fn from_io(e: &io::Error) -> Option<&ParseError> {
+        e.get_ref()?.downcast_ref::<ParseError>()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            ParseError::MalformedRequestLine => "malformed request line",
+            ParseError::MalformedHeader => "malformed header line",
+            ParseError::HeaderTooLarge => "request head is too large",
+            ParseError::UriTooLong => "request-target is too long",
+            ParseError::MalformedUri => "malformed request-target",
+            ParseError::UnsupportedMethod => "unsupported method",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::InvalidContentLength => "invalid Content-Length",
+            ParseError::UnsupportedTransferEncoding => "unsupported Transfer-Encoding",
+            ParseError::ConflictingFraming => "conflicting Content-Length and Transfer-Encoding",
+            ParseError::BadChunk => "bad chunk framing",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
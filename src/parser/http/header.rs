@@ -0,0 +1,198 @@
+//! HTTP header map
+//!
+//! Header names are case-insensitive, a header may appear more than once
+//! (`Set-Cookie`, `Accept`), and headers are kept in the order they were added.
+//!
+//! ref: https://tools.ietf.org/html/rfc7230#section-3.2
+
+use std::fmt;
+
+/// Case-insensitive, multi-value, ordered header map
+///
+/// Used by both `HttpRequest` and `HttpResponse`.
+#[derive(Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl fmt::Debug for HeaderMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        HeaderMap { entries: Vec::new() }
+    }
+
+    /// Get the first value of header `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get all values of header `name`, in the order they were added
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries.iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set header `name` to `value`, all old values of `name` are replaced
+    ///
+    /// The header keeps its position if it already exists.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
+        let name = name.into();
+        match self.entries.iter().position(|(k, _)| k.eq_ignore_ascii_case(&name)) {
+            Some(pos) => {
+                self.entries[pos].1 = value.into();
+                let mut index = 0;
+                self.entries.retain(|(k, _)| {
+                    index += 1;
+                    index - 1 == pos || !k.eq_ignore_ascii_case(&name)
+                });
+            }
+            None => self.entries.push((name, value.into())),
+        }
+    }
+
+    /// Add a new value to header `name`, old values are kept
+    pub fn append<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Remove all values of header `name`, return the first one
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        let mut index = 0;
+        while index < self.entries.len() {
+            if self.entries[index].0.eq_ignore_ascii_case(name) {
+                let (_, v) = self.entries.remove(index);
+                removed.get_or_insert(v);
+            } else {
+                index += 1;
+            }
+        }
+        removed
+    }
+
+    /// Iterate over all `(name, value)` pairs, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check if comma-separated header `name` contains `option` (case-insensitive)
+    ///
+    /// e.g. `Connection: keep-alive, Upgrade` contains `upgrade`
+    pub fn has_option(&self, name: &str, option: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|i| i.trim().eq_ignore_ascii_case(option))
+    }
+
+    /// `Content-Length`, `None` if it is not assigned or invalid
+    pub fn content_length(&self) -> Option<u64> {
+        let length = self.get("Content-Length")?;
+        // `1*DIGIT`, `u64::from_str` also takes a leading `+`
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return None
+        }
+        length.parse().ok()
+    }
+
+    /// Media type in `Content-Type`, parameters like `charset` are removed
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")?.split(';').next().map(|i| i.trim())
+    }
+
+    /// Parameter `param` in `Content-Type`, e.g. `boundary` of `multipart/form-data`
+    pub fn content_type_param(&self, param: &str) -> Option<&str> {
+        self.get("Content-Type")?
+            .split(';')
+            .skip(1)
+            .filter_map(|i| {
+                let mut kv = i.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case(param) => Some(v.trim().trim_matches('"')),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// Check if body is encoded by `Transfer-Encoding: chunked`
+    pub fn is_chunked(&self) -> bool {
+        self.has_option("Transfer-Encoding", "chunked")
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_insensitive_lookup() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-length", "12");
+        assert_eq!(headers.get("Content-Length"), Some("12"));
+        assert_eq!(headers.get("CONTENT-LENGTH"), Some("12"));
+        assert_eq!(headers.content_length(), Some(12));
+        headers.insert("content-length", "13");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.content_length(), Some(13));
+        assert_eq!(headers.remove("Content-Length"), Some("13".to_string()));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn multi_value_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Server", "rhttp");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(headers.get_all("Set-Cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(headers.iter().map(|(k, _)| k).collect::<Vec<_>>(), ["Set-Cookie", "Server", "set-cookie"]);
+        headers.insert("SET-COOKIE", "c=3");
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("Set-Cookie", "c=3"), ("Server", "rhttp")]);
+    }
+
+    #[test]
+    fn typed_accessors() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "multipart/form-data; boundary=\"abc\"");
+        headers.insert("Transfer-Encoding", "gzip, Chunked");
+        headers.insert("Connection", "keep-alive, Upgrade");
+        assert_eq!(headers.content_type(), Some("multipart/form-data"));
+        assert_eq!(headers.content_type_param("boundary"), Some("abc"));
+        assert!(headers.is_chunked());
+        assert!(headers.has_option("Connection", "upgrade"));
+        assert_eq!(headers.content_length(), None);
+    }
+}
@@ -1,21 +1,19 @@
-use std::fs;
-use std::collections::BTreeMap;
-
 use super::super::*;
+use super::utils::path::*;
 use crate::Config;
 
 // use super::utils::chunk::*;
 
-/// Generate HttpResponse for POST method
+/// Generate HttpResponse for GET method
 /// 
 /// Some final work is done by `handle_connection`.
 /// 
-/// Binary file read is done by `handle_connection`,
+/// Requested file is returned as `Body::File`, it is never read into `String`,
 /// for `String` can not deal with binary file. (will lead to data damage)
 ///
 /// * Return `Some(HttpResponse)` if a http response is required.
 /// * Return `None` will close the TCP link or do nothing.
-pub fn generate_get_response<'t>(request: &mut HttpRequest, mut headers: BTreeMap::<String, String>, cfg: &Config) -> Option<HttpResponse<'t>> {
+pub fn generate_get_response<'t>(request: &mut HttpRequest, headers: HeaderMap, cfg: &Config) -> Option<HttpResponse<'t>> {
     let root_dir: &str = &cfg.root_dir;
 
     // Sending body/payload in a GET request may cause some existing
@@ -30,59 +28,43 @@ pub fn generate_get_response<'t>(request: &mut HttpRequest, mut headers: BTreeMa
     //     println!("warning: GET request contains body/payload");
     // }
 
-    // check if requsested resource exists
-    let filename = if request.url == "/" {
-        format!("{}/index.html", root_dir)
-    } else {
-        format!("{}/{}", root_dir, request.url)
-    };
-    match fs::File::open(&filename) {
+    // check if requsested resource exists, and is inside root_dir
+    match resolve_path(root_dir, request.url, cfg.follow_symlinks, Access::Read) {
         // if resource exists, return 200
-        Ok(_) => {
-            let body = match fs::read_to_string(&filename) {
-                Ok(s) => s,
-                Err(_) => {
-                    // transfer binary
-                    return Some( HttpResponse {
-                        status_code: 200,
-                        status_text: "OK",
-                        headers: headers,
-                        body: None, // read body from raw file outside
-                    })
-                }
+        Ok(filename) => {
+            // file is opened here and sent as is, text and binary files are the same
+            let body = match Body::from_file(&filename) {
+                Ok(body) => body,
+                // file may be removed by other threads
+                Err(_) => return Some(HttpResponse::error_500()),
             };
-            let content_length = body.chars().count();
-            // println!("{}", body);
-            
-            // chunk resp is not enabled by default 
-            let chunked = false;
-            // if chunked {
-                //     // chunk resp, if needed
-                //     return Some( HttpResponse {
-                    //         status_code: 200,
-                    //         status_text: "OK",
-                    //         headers: headers,
-                    //         body: Some(string_to_chunk(&body)),
-                    //     })
-                    // } else {
-            return Some( HttpResponse { // chunklize was moved outsize
+            // chunk resp is not enabled by default, chunklize was moved outside
+            Some( HttpResponse {
                 status_code: 200,
                 status_text: "OK",
-                headers: headers,
-                body: Some(body),
+                headers,
+                body,
+                trailers: HeaderMap::new(),
             })
-            // }
         } 
+        // if resource is outside root_dir, return 403
+        Err(PathError::Forbidden) => Some(HttpResponse::error_403()),
         // if resource dose not exist, return 404
-        _ => {
-            let body = fs::read_to_string(format!("{}/error/404.html", root_dir)).unwrap();
-            headers.insert("Content-Length".to_string(), body.chars().count().to_string());
-            return Some( HttpResponse {
-                status_code: 404,
-                status_text: "NOT FOUND",
-                headers: headers,
-                body: Some(body),
-            })
-        }
+        Err(PathError::NotFound) => Some(not_found_response(root_dir, headers)),
+    }
+}
+
+/// 404 response with `error/404.html` of `root_dir`, or a plain text one
+pub fn not_found_response<'t>(root_dir: &str, headers: HeaderMap) -> HttpResponse<'t> {
+    let body = match Body::from_file(format!("{}/error/404.html", root_dir)) {
+        Ok(body) => body,
+        Err(_) => "404 Not Found".into(),
+    };
+    HttpResponse {
+        status_code: 404,
+        status_text: "NOT FOUND",
+        headers,
+        body,
+        trailers: HeaderMap::new(),
     }
 }
\ No newline at end of file
@@ -1,7 +1,6 @@
-use std::fs;
-use std::collections::BTreeMap;
-
 use super::super::*;
+use super::get::not_found_response;
+use super::utils::path::*;
 use crate::Config;
 
 /// Generate HttpResponse for HEAD method
@@ -10,36 +9,36 @@ use crate::Config;
 /// 
 /// * Return `Some(HttpResponse)` if a http response is required.
 /// * Return `None` will close the TCP link or do nothing.
-pub fn generate_head_response<'t>(request: &mut HttpRequest, headers: BTreeMap::<String, String>, cfg: &Config) -> Option<HttpResponse<'t>> {
+pub fn generate_head_response<'t>(request: &mut HttpRequest, headers: HeaderMap, cfg: &Config) -> Option<HttpResponse<'t>> {
     let root_dir: &str = &cfg.root_dir;
 
     // almost the same as GET
-    // check if requsested resource exists
-    let filename = if request.url == "/" {
-        format!("{}/index.html", root_dir)
-    } else {
-        format!("{}/{}", root_dir, request.url)
-    };
-    match fs::File::open(&filename) {
-        // if resource exists, return 200
-        Ok(_) => {
-            let body = fs::read_to_string(&filename).unwrap();
-            return Some( HttpResponse {
+    // check if requsested resource exists, and is inside root_dir
+    match resolve_path(root_dir, request.url, cfg.follow_symlinks, Access::Read) {
+        // if resource exists, return 200 with the length GET would send
+        Ok(filename) => {
+            let len = match std::fs::metadata(&filename) {
+                Ok(meta) => meta.len(),
+                // file may be removed by other threads
+                Err(_) => return Some(HttpResponse::error_500()),
+            };
+            Some( HttpResponse {
                 status_code: 200,
                 status_text: "OK",
-                headers: headers,
-                body: Some("".to_string()),
+                headers,
+                body: Body::Omitted(len),
+                trailers: HeaderMap::new(),
             })
         } 
+        // error responses also tell the length of what GET would send
+        Err(PathError::Forbidden) => Some(omit_body(HttpResponse::error_403())),
         // if resource dose not exist, return 404
-        _ => {
-            let body = fs::read_to_string(format!("{}/error/404.html", root_dir)).unwrap();
-            return Some( HttpResponse {
-                status_code: 404,
-                status_text: "NOT FOUND",
-                headers: headers,
-                body: Some("".to_string()),
-            })
-        }
+        Err(PathError::NotFound) => Some(omit_body(not_found_response(root_dir, headers))),
     }
+}
+
+/// Keep the length of `response` body, without sending it
+fn omit_body(mut response: HttpResponse) -> HttpResponse {
+    response.body = Body::Omitted(response.body.len().unwrap_or(0));
+    response
 }
\ No newline at end of file
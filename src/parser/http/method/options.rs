@@ -1,4 +1,3 @@
-use std::collections::BTreeMap;
 
 use super::super::*;
 use crate::Config;
@@ -9,15 +8,16 @@ use crate::Config;
 /// 
 /// * Return `Some(HttpResponse)` if a http response is required.
 /// * Return `None` will close the TCP link or do nothing.
-pub fn generate_options_response<'t>(_request: &mut HttpRequest, mut headers: BTreeMap::<String, String>, cfg: &Config) -> Option<HttpResponse<'t>> {
+pub fn generate_options_response<'t>(_request: &mut HttpRequest, mut headers: HeaderMap, cfg: &Config) -> Option<HttpResponse<'t>> {
     let _root_dir: &str = &cfg.root_dir;
 
     // ref: https://developer.mozilla.org/en-US/docs/Web/HTTP/Methods/OPTIONS
     headers.insert("Allow".to_string(), "OPTIONS, GET, PUT, POST, HEAD".to_string());
-    return Some( HttpResponse {
+    Some( HttpResponse {
         status_code: 204,
         status_text: "No Content",
-        headers: headers,
-        body: Some("".to_string()),
+        headers,
+        body: Body::Empty,
+        trailers: HeaderMap::new(),
     })
 }
\ No newline at end of file
@@ -1,9 +1,9 @@
 use std::fs;
-use std::collections::BTreeMap;
+use std::io::prelude::*;
 
-use super::super::BUFFER_SIZE;
 use super::super::*;
-use super::utils::chunk::*;
+use super::utils::upload::*;
+use super::utils::path::*;
 use crate::Config;
 
 /// Generate HttpResponse for POST method
@@ -14,198 +14,97 @@ use crate::Config;
 /// * Return `None` will close the TCP link or do nothing.
 /// 
 /// Extra code/function can be added to deal with post request body.
-pub fn generate_post_response<'t>(request: &mut HttpRequest, headers: BTreeMap::<String, String>, cfg: &Config) -> Option<HttpResponse<'t>> {
+pub fn generate_post_response<'t>(request: &mut HttpRequest, headers: HeaderMap, cfg: &Config) -> Option<HttpResponse<'t>> {
     let root_dir: &str = &cfg.root_dir;
 
-    let raw_length = match request.headers.get("Content-Length") {
-        Some(i) => i,
-        None => return None,
-    };
-    let length = match raw_length.parse::<usize>() {
-        Ok(i) => i,
-        Err(_) => {
-            println!("len parse failed");
-            return None
-        },
-    };
-    
-    // length check
-    if length >= BUFFER_SIZE {
-        return Some(HttpResponse::error_507())
-    }
-    
-    let content_type = match request.headers.get("Content-Type") {
-        Some(i) => i,
+    // body framing (Content-Length or chunked) has been removed by `RequestReader`,
+    // body size is limited by `max_body_size`
+    let content_type = match request.headers.content_type() {
+        Some(i) => i.to_string(),
         _ => return Some(HttpResponse::error_400())
     };
-    let filename = format!("{}/{}", root_dir, request.url);
-    let chunked = match headers.get("Transfer-Encoding") {
-        Some(i) => i == "chunked",
-        _ => false
-    };
-    match content_type {
-        &"application/x-www-form-urlencoded" => {
-            let mut content = BTreeMap::<String, String>::new();
-            
-            // FIXME: reundent code
-            if chunked { // FIXME: perf loss for runtime copying
-                let recovered_string = chunklines_to_string(&mut request.body);
-                let mut lines = recovered_string.lines();
-                loop {
-                    if let Some(line) = lines.next() {
-                        let kv_pair = line.split("&");
-                        for i in kv_pair {
-                            let mut j = i.split("=");
-                            if let Some(k) = j.next() {
-                                if let Some(v) = j.next() {
-                                    content.insert(k.to_string(), v.to_string());
-                                }
-                            }
-                        }
-                    } else {
-                        break;
-                    }
-                }
-            } else {
-                loop {
-                    if let Some(line) = request.body.next() {
-                        let kv_pair = line.split("&");
-                        for i in kv_pair {
-                            let mut j = i.split("=");
-                            if let Some(k) = j.next() {
-                                if let Some(v) = j.next() {
-                                    content.insert(k.to_string(), v.to_string());
-                                }
-                            }
-                        }
-                    } else {
-                        break;
-                    }
-                }
-            };
+    match content_type.as_str() {
+        "application/x-www-form-urlencoded" => {
+            let mut content = Vec::new();
+            if let Err(e) = request.body.read_to_end(&mut content) {
+                return Some(HttpResponse::from_body_error(&e))
+            }
+            // form body uses the same encoding as query string
+            let kv_pairs = Query::parse(String::from_utf8_lossy(&content).trim_end());
 
             // TODO: you can add extra data process here 
-            println!("received kvpairs from POST: {:#?}", content);
+            println!("received kvpairs from POST: {:#?}", kv_pairs);
             
-            return Some( HttpResponse {
+            Some( HttpResponse {
                 status_code: 200,
                 status_text: "OK",
-                headers: headers,
-                body: Some("".to_string()),
+                headers,
+                body: Body::Empty,
+                trailers: HeaderMap::new(),
             })
         },
-        &"text/plain" => {
-            // transfer data to server
-            // get content from request
-            let mut content = String::new();
-            let mut newline = false;
-            if chunked {
-                content = chunklines_to_string(&mut request.body);
-            } else {
-                loop {
-                    if let Some(i) = request.body.next() {
-                        if newline { content.push_str("\n") };
-                        content.push_str(i);
-                        newline = true;
-                    } else {
-                        break;
-                    }
-                }
-            }
-            match fs::File::open(&filename) {
+        "text/plain" | "application/octet-stream" => {
+            // transfer data to server, body is written byte-for-byte
+            // file to be written must be inside root_dir
+            let filename = match resolve_path(root_dir, request.url, cfg.follow_symlinks, Access::Write) {
+                Ok(filename) => filename,
+                Err(e) => return Some(HttpResponse::from_path_error(&e)),
+            };
+            let existed = fs::File::open(&filename).is_ok();
+            match save_body(&filename, &mut request.body) {
                 Ok(_) => {
-                    // if resource exists, try to update it
-                    match fs::write(&filename, content) {
-                        Ok(_) => {
-                            return Some( HttpResponse {
-                                status_code: 200,
-                                status_text: "OK",
-                                headers: headers,
-                                body: Some(format!("Content-Location: {}", request.url).to_string()),
-                            })
-                        }
-                        _ => {
-                            return Some(HttpResponse::error_500())
-                        }
-                    }
-                } 
-                // if resource dose not exist, create it
-                _ => {
-                    match fs::write(&filename, content) {
-                        Ok(_) => {
-                            return Some( HttpResponse {
-                                status_code: 201,
-                                status_text: "Created",
-                                headers: headers,
-                                body: Some(format!("Content-Location: {}", request.url).to_string()),
-                            })
-                        }
-                        _ => {
-                            return Some(HttpResponse::error_500())
-                        }
-                    }
+                    // if resource exists, it is updated, otherwise it is created
+                    let (status_code, status_text) = if existed { (200, "OK") } else { (201, "Created") };
+                    Some( HttpResponse {
+                        status_code,
+                        status_text,
+                        headers,
+                        body: format!("Content-Location: {}", request.path).into(),
+                        trailers: HeaderMap::new(),
+                    })
                 }
+                Err(e) => Some(HttpResponse::from_body_error(&e))
             }
         },
-        x => {
-            if !x.starts_with("multipart/form-data") {
-                return Some(HttpResponse::error_405())
-            } else {
-                // get boundry
-                let mut value_splited = x.split("boundry=");
-                let boundry = match value_splited.next() {
-                    Some(_) => {
-                        match value_splited.next() {
-                            Some(i) => i,
-                            _ => return Some(HttpResponse::error_400()) 
-                        }
-                    },
-                    _ => return Some(HttpResponse::error_400())
-                };
-                let mut content = Vec::<String>::new();
-                let mut new_part = String::new();
-
-                // FIXME: reundent code
-                if chunked { // FIXME: perf loss for runtime copying
-                    let recovered_string = chunklines_to_string(&mut request.body);
-                    let mut lines = recovered_string.lines();
-                    while let Some(line) = lines.next() {
-                        if line.starts_with(&format!("--{}--", boundry)) {
-                            break
-                        }
-                        if line.starts_with(&format!("--{}", boundry)) && new_part.chars().count() > 0 {
-                            content.push(new_part.clone()); // content get old new_part 
-                            new_part = String::new();
-                        } else {
-                            new_part.push_str(line);
-                            new_part.push_str("\n");
-                        }
+        "multipart/form-data" => {
+            // get boundary
+            let boundary = match request.headers.content_type_param("boundary") {
+                Some(i) => i.to_string(),
+                _ => return Some(HttpResponse::error_400())
+            };
+            let mut content = Vec::new();
+            if let Err(e) = request.body.read_to_end(&mut content) {
+                return Some(HttpResponse::from_body_error(&e))
+            }
+            let mut parts = Vec::<String>::new();
+            let mut new_part = String::new();
+            for line in String::from_utf8_lossy(&content).lines() {
+                if line.starts_with(&format!("--{}--", boundary)) {
+                    if !new_part.is_empty() {
+                        parts.push(new_part);
                     }
+                    break
+                }
+                if line.starts_with(&format!("--{}", boundary)) && !new_part.is_empty() {
+                    parts.push(new_part); // parts get old new_part 
+                    new_part = String::new();
                 } else {
-                    while let Some(line) = request.body.next() {
-                        if line.starts_with(&format!("--{}--", boundry)) {
-                            break
-                        }
-                        if line.starts_with(&format!("--{}", boundry)) && new_part.chars().count() > 0 {
-                            content.push(new_part.clone()); // content get old new_part 
-                            new_part = String::new();
-                        } else {
-                            new_part.push_str(line);
-                            new_part.push_str("\n");
-                        }
-                    }
+                    new_part.push_str(line);
+                    new_part.push('\n');
                 }
-                
-                // TODO: you can add extra data process here 
-                println!("received multipart from POST: {:#?}", content);
-                
-                return Some( HttpResponse {
-                    status_code: 200,
-                    status_text: "OK",
-                    headers: headers,
-                    body: Some("".to_string()),
-                })
             }
+            
+            // TODO: you can add extra data process here 
+            println!("received multipart from POST: {:#?}", parts);
+            
+            Some( HttpResponse {
+                status_code: 200,
+                status_text: "OK",
+                headers,
+                body: Body::Empty,
+                trailers: HeaderMap::new(),
+            })
         }
+        _ => Some(HttpResponse::error_405())
     }
-}
\ No newline at end of file
+}
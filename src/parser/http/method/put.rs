@@ -1,8 +1,8 @@
 use std::fs;
-use std::collections::BTreeMap;
 
-use super::super::BUFFER_SIZE;
 use super::super::*;
+use super::utils::upload::*;
+use super::utils::path::*;
 use crate::Config;
 
 /// Generate HttpResponse for PUT method
@@ -11,67 +11,29 @@ use crate::Config;
 /// 
 /// * Return `Some(HttpResponse)` if a http response is required.
 /// * Return `None` will close the TCP link or do nothing.
-pub fn generate_put_response<'t>(request: &mut HttpRequest, headers: BTreeMap::<String, String>, cfg: &Config) -> Option<HttpResponse<'t>> {
+pub fn generate_put_response<'t>(request: &mut HttpRequest, headers: HeaderMap, cfg: &Config) -> Option<HttpResponse<'t>> {
     let root_dir: &str = &cfg.root_dir;
 
-    let raw_length = match request.headers.get("Content-length") {
-        Some(i) => i,
-        None => return None,
+    // file to be written must be inside root_dir
+    let filename = match resolve_path(root_dir, request.url, cfg.follow_symlinks, Access::Write) {
+        Ok(filename) => filename,
+        Err(e) => return Some(HttpResponse::from_path_error(&e)),
     };
-    let length = match raw_length.parse::<usize>() {
-        Ok(i) => i,
-        Err(_) => return None,
-    };
-
-    // length check
-    if length >= BUFFER_SIZE {
-        return Some(HttpResponse::error_507())
-    }
-    // get content from request
-    let mut content = String::new();
-    let mut newline = false;
-    loop {
-        if let Some(i) = request.body.next() {
-            if newline { content.push_str("\n") };
-            content.push_str(i);
-            newline = true;
-        } else {
-            break;
-        }
-    }
-    let filename = format!("{}/{}", root_dir, request.url);
-    match fs::File::open(&filename) {
+    let existed = fs::File::open(&filename).is_ok();
+    // body framing (Content-Length or chunked) has been removed by `RequestReader`,
+    // body is copied to file as raw bytes, binary file will be written byte-for-byte
+    match save_body(&filename, &mut request.body) {
         Ok(_) => {
-            // if resource exists, try to update it
-            match fs::write(&filename, content) {
-                Ok(_) => {
-                    return Some( HttpResponse {
-                        status_code: 200,
-                        status_text: "OK",
-                        headers: headers,
-                        body: Some(format!("Content-Location: {}", request.url).to_string()),
-                    })
-                }
-                _ => {
-                    return Some(HttpResponse::error_500())
-                }
-            }
-        } 
-        // if resource dose not exist, create it
-        _ => {
-            match fs::write(&filename, content) {
-                Ok(_) => {
-                    return Some( HttpResponse {
-                        status_code: 201,
-                        status_text: "Created",
-                        headers: headers,
-                        body: Some(format!("Content-Location: {}", request.url).to_string()),
-                    })
-                }
-                _ => {
-                    return Some(HttpResponse::error_500())
-                }
-            }
+            // if resource exists, it is updated, otherwise it is created
+            let (status_code, status_text) = if existed { (200, "OK") } else { (201, "Created") };
+            Some( HttpResponse {
+                status_code,
+                status_text,
+                headers,
+                body: format!("Content-Location: {}", request.path).into(),
+                trailers: HeaderMap::new(),
+            })
         }
+        Err(e) => Some(HttpResponse::from_body_error(&e))
     }
-}
\ No newline at end of file
+}
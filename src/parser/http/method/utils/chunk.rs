@@ -1,102 +1,101 @@
 //! Chunk encode & decode lib
+//!
+//! ref: https://tools.ietf.org/html/rfc7230#section-4.1
 
-/// Generate a new chunked `String` from &str
-/// 
-/// Can be used to generate chunked data from `String`.
-pub fn string_to_chunk(input: &str) -> String {
-    let len = input.chars().count();
-    let mut pos = 0;
-    let mut s = String::new();
-    let chunksize = 8;
-    
-    while pos < len {
-        if (len - pos) > chunksize {
-            s.push_str(r"8\r\n");
-            s.push_str(&input[pos..pos+chunksize]);
-            s.push_str(r"\r\n");
-            pos += chunksize;
-        } else {
-            s.push_str(&format!("{}", (len - pos)));
-            s.push_str(r"\r\n");
-            s.push_str(&input[pos..len]);
-            s.push_str(r"\r\n");
-            s.push_str(r"0\r\n");
-            break
+use std::io;
+use std::io::prelude::*;
+
+use super::super::super::HeaderMap;
+
+/// Default size of each chunk
+pub const DEFAULT_CHUNK_SIZE: usize = 8192;
+
+/// Streaming chunked encoder
+///
+/// Bytes written are sent as chunks of `chunk_size` bytes,
+/// `finish` sends the last chunk and trailers.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    /// Bytes waiting to be sent as a chunk
+    buffer: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W, chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1);
+        ChunkedWriter {
+            inner,
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
         }
     }
-    s
-}
 
-/// Generate a new chunked `String` from &Vec<u8>
-/// 
-/// Can be used to generate chunked data from binary file.
-pub fn vec_to_chunk(input: &Vec<u8>) -> Vec<u8> {
-    let len = input.len();
-    let mut pos = 0;
-    let mut s = Vec::<u8>::new();
-    let chunksize = 8;
-    
-    while pos < len {
-        if pos % chunksize == 0 {
-            s.push(b'\r');
-            s.push(b'\n');
-            if pos + chunksize >= len {
-                s.push((len - pos) as u8 + b'0');
-            } else {
-                s.push(chunksize as u8 + b'0');
-            }
-            s.push(b'\r');
-            s.push(b'\n');
+    /// Send `data` as a chunk: size in hex, CRLF, data, CRLF
+    fn write_chunk(inner: &mut W, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            // empty chunk means end of body
+            return Ok(())
         }
-        s.push(input[pos]);
-        pos += 1;
+        write!(inner, "{:x}\r\n", data.len())?;
+        inner.write_all(data)?;
+        inner.write_all(b"\r\n")
     }
-    s.push(b'\r');
-    s.push(b'\n');
-    s.push(b'0');
-    s.push(b'\r');
-    s.push(b'\n');
-    s
-}
 
-/// Generate a new chunked `String` from &Vec<u8>
-/// 
-/// Can be used to generate chunked data from line iterator.
-/// 
-/// Outdated.
-pub fn chunklines_to_string(lines: &mut std::str::Lines) -> String {
-    let mut s = String::new();    
-    loop {
-        let mut _len = 0; // use _ prefix to stop rustc from complaining
-        // better design pattern needed
+    /// Inner stream, chunks sent so far have been written to it
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
 
-        match lines.next() {
-            Some(i) => {
-                // read hex
-                match usize::from_str_radix(i, 16) {
-                    Ok(v) => { 
-                        if v == 0 { break }
-                        _len = v; 
-                    }
-                    _ => break // error
-                }
-            }
-            _ => break // error
+    /// Send buffered bytes and the last chunk, then `trailers`
+    ///
+    /// Return the inner stream.
+    pub fn finish(mut self, trailers: &HeaderMap) -> io::Result<W> {
+        Self::write_chunk(&mut self.inner, &self.buffer)?;
+        self.inner.write_all(b"0\r\n")?;
+        for (k, v) in trailers {
+            write!(self.inner, "{}: {}\r\n", k, v)?;
         }
-        // read content
-        match lines.next() {
-            Some(j) => {
-                if j.chars().count() == _len {
-                    s.push_str(j);
-                } else {
-                    println!("chunk len {} and real len {} does not match", _len, j.chars().count());
-                    break
-                }
-            }
-            _ => break
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..size]);
+        if self.buffer.len() == self.chunk_size {
+            Self::write_chunk(&mut self.inner, &self.buffer)?;
+            self.buffer.clear();
         }
+        Ok(size)
+    }
+
+    /// Send buffered bytes as a (short) chunk
+    fn flush(&mut self) -> io::Result<()> {
+        Self::write_chunk(&mut self.inner, &self.buffer)?;
+        self.buffer.clear();
+        self.inner.flush()
     }
-    s
+}
+
+/// Generate a new chunked `String` from &str
+///
+/// Can be used to generate chunked data from `String`.
+pub fn string_to_chunk(input: &str, chunk_size: usize) -> String {
+    String::from_utf8_lossy(&vec_to_chunk(input.as_bytes(), chunk_size)).into_owned()
+}
+
+/// Generate a new chunked `Vec<u8>` from &[u8]
+///
+/// Can be used to generate chunked data from binary file.
+pub fn vec_to_chunk(input: &[u8], chunk_size: usize) -> Vec<u8> {
+    let mut writer = ChunkedWriter::new(Vec::new(), chunk_size);
+    // writing to Vec never fails
+    writer.write_all(input).unwrap();
+    writer.finish(&HeaderMap::new()).unwrap()
 }
 
 #[cfg(test)]
@@ -104,10 +103,23 @@ mod chunk_test {
     use super::*;
     #[test]
     fn convert_rawbody_to_chunk() {
-        let raw_body = r"012345678901234567890123456789"; 
+        let raw_body = r"012345678901234567890123456789";
         let right_body =
-r"8\r\n01234567\r\n8\r\n89012345\r\n8\r\n67890123\r\n6\r\n456789\r\n0\r\n";
-        let body = string_to_chunk(raw_body);
+"8\r\n01234567\r\n8\r\n89012345\r\n8\r\n67890123\r\n6\r\n456789\r\n0\r\n\r\n";
+        let body = string_to_chunk(raw_body, 8);
         assert_eq!(body, right_body);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn chunk_with_trailers() {
+        let mut writer = ChunkedWriter::new(Vec::new(), 16);
+        writer.write_all(&[b'a'; 26]).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"bc").unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("Expires", "0");
+        let output = writer.finish(&trailers).unwrap();
+        let expected = format!("10\r\n{}\r\na\r\n{}\r\n2\r\nbc\r\n0\r\nExpires: 0\r\n\r\n", "a".repeat(16), "a".repeat(10));
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
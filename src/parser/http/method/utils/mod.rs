@@ -1,6 +1,9 @@
 //! Utils for HTTP methods
 
 pub mod chunk;
+pub mod upload;
+pub mod path;
 
-pub use chunk::string_to_chunk;
-pub use chunk::chunklines_to_string;
\ No newline at end of file
+pub use chunk::{string_to_chunk, ChunkedWriter};
+pub use upload::save_body;
+pub use path::{resolve_path, Access, PathError};
\ No newline at end of file
@@ -0,0 +1,143 @@
+//! Resolve request path to a file under `root_dir`
+//!
+//! All handlers use `resolve_path`, so no request can reach files outside `root_dir`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use super::super::super::uri;
+
+/// Reason why a request path can not be resolved
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    /// Path escapes `root_dir`, or contains forbidden bytes: 403
+    Forbidden,
+    /// File, or dir of the file to be written, does not exist: 404
+    NotFound,
+}
+
+/// How the resolved file will be used
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    /// File must exist, dir is resolved to its `index.html`
+    Read,
+    /// File may not exist, but its dir must exist
+    Write,
+}
+
+/// Decode and normalize path segments of request-target
+///
+/// `.` and empty segments are removed, `..` removes the previous segment.
+/// Encoded `/`, `\` and NUL are rejected, for they would change how the path is split.
+fn normalize(url: &str) -> Result<Vec<String>, PathError> {
+    let (raw_path, _) = uri::split_target(url);
+    if !raw_path.starts_with('/') {
+        return Err(PathError::Forbidden)
+    }
+    let mut segments = Vec::new();
+    for raw_segment in raw_path.split('/') {
+        let segment = match uri::percent_decode(raw_segment, false).map(String::from_utf8) {
+            Some(Ok(segment)) => segment,
+            _ => return Err(PathError::Forbidden),
+        };
+        if segment.contains(['/', '\\', '\0']) {
+            return Err(PathError::Forbidden)
+        }
+        match segment.as_str() {
+            "" | "." => {},
+            ".." => {
+                // going above root_dir is never allowed
+                if segments.pop().is_none() {
+                    return Err(PathError::Forbidden)
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    Ok(segments)
+}
+
+/// Resolve request-target `url` to a file under `root_dir`
+///
+/// If `follow_symlinks` is false, symlinks pointing outside `root_dir` are refused.
+pub fn resolve_path(root_dir: &str, url: &str, follow_symlinks: bool, access: Access) -> Result<PathBuf, PathError> {
+    let mut path = PathBuf::from(root_dir);
+    path.extend(normalize(url)?);
+
+    match access {
+        Access::Read => {
+            if path.is_dir() {
+                path.push("index.html");
+            }
+            if !path.is_file() {
+                return Err(PathError::NotFound)
+            }
+        }
+        Access::Write => {
+            if path.is_dir() {
+                return Err(PathError::Forbidden)
+            }
+            match path.parent() {
+                Some(dir) if dir.is_dir() => {},
+                _ => return Err(PathError::NotFound),
+            }
+        }
+    }
+
+    if !follow_symlinks {
+        let root = fs::canonicalize(root_dir).map_err(|_| PathError::NotFound)?;
+        // file to be written may not exist, check its dir then
+        let target = if path.symlink_metadata().is_ok() {
+            fs::canonicalize(&path)
+        } else {
+            path.parent().map_or(Err(std::io::ErrorKind::NotFound.into()), fs::canonicalize)
+        };
+        match target {
+            Ok(target) if target.starts_with(&root) => {},
+            _ => return Err(PathError::Forbidden),
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_under_root() {
+        let root = std::env::temp_dir().join(format!("rhttp-path-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/index.html"), "index").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let root_str = root.to_str().unwrap();
+        let resolve = |url: &str, access| resolve_path(root_str, url, false, access);
+
+        assert_eq!(resolve("/dir/../a.txt?v=1", Access::Read), Ok(root.join("a.txt")));
+        assert_eq!(resolve("/./dir/", Access::Read), Ok(root.join("dir/index.html")));
+        assert_eq!(resolve("/b.txt", Access::Read), Err(PathError::NotFound));
+        assert_eq!(resolve("/b.txt", Access::Write), Ok(root.join("b.txt")));
+        assert_eq!(resolve("/no/b.txt", Access::Write), Err(PathError::NotFound));
+        assert_eq!(resolve("/dir", Access::Write), Err(PathError::Forbidden));
+
+        assert_eq!(resolve("/../etc/passwd", Access::Read), Err(PathError::Forbidden));
+        assert_eq!(resolve("/dir/%2e%2e/%2E%2E/etc/passwd", Access::Read), Err(PathError::Forbidden));
+        assert_eq!(resolve("/..%2fetc/passwd", Access::Read), Err(PathError::Forbidden));
+        assert_eq!(resolve("/..%5cetc", Access::Read), Err(PathError::Forbidden));
+        assert_eq!(resolve("/a.txt%00.html", Access::Read), Err(PathError::Forbidden));
+
+        #[cfg(unix)]
+        {
+            let outside = std::env::temp_dir().join(format!("rhttp-path-outside-{}", std::process::id()));
+            fs::create_dir_all(&outside).unwrap();
+            fs::write(outside.join("secret.txt"), "secret").unwrap();
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            assert_eq!(resolve("/link/secret.txt", Access::Read), Err(PathError::Forbidden));
+            assert_eq!(resolve("/link/new.txt", Access::Write), Err(PathError::Forbidden));
+            assert_eq!(resolve_path(root_str, "/link/secret.txt", true, Access::Read), Ok(root.join("link/secret.txt")));
+            fs::remove_dir_all(&outside).unwrap();
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
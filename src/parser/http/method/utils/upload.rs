@@ -0,0 +1,28 @@
+//! Save request body to file
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counter to generate unique temp file names
+static UPLOAD_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Copy request body stream into file `filename`
+/// 
+/// Body is written to a temp file first and renamed to `filename` when finished,
+/// so a broken or rejected upload will not damage the existing file.
+/// 
+/// Return the number of bytes written.
+pub fn save_body(filename: &Path, body: &mut dyn Read) -> io::Result<u64> {
+    let mut tmp_filename = filename.as_os_str().to_owned();
+    tmp_filename.push(format!(".{}.rhttp-upload", UPLOAD_ID.fetch_add(1, Ordering::Relaxed)));
+    let result = fs::File::create(&tmp_filename)
+        .and_then(|mut file| io::copy(body, &mut file))
+        .and_then(|size| fs::rename(&tmp_filename, filename).map(|_| size));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_filename);
+    }
+    result
+}
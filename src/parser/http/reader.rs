@@ -0,0 +1,545 @@
+//! Incremental HTTP request reader
+//!
+//! A request may be split across many TCP segments, so a single `read` is
+//! not enough. `RequestReader` keeps reading until the whole head is received,
+//! then exposes body as a `Read` stream, which reads exactly `Content-Length`
+//! bytes or decodes chunked body on the fly.
+//!
+//! Bytes received after the end of a request are kept for the next one.
+//! 
+//! Chunked body is decoded byte by byte, chunk data may contain any bytes.
+//! ref: https://tools.ietf.org/html/rfc7230#section-4.1
+//!
+//! A `WouldBlock` error from stream never loses bytes, the same call can be
+//! made again when more bytes arrive. So the reader also works on a buffer
+//! filled by non-blocking or async code.
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use super::*;
+
+/// Size of each read from the stream
+const READ_SIZE: usize = 4096;
+
+/// Error raised when request body is larger than the allowed size
+#[derive(Debug)]
+pub struct PayloadTooLarge;
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request body is too large")
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Check if an io error is raised by a too large request body
+pub fn is_payload_too_large(e: &io::Error) -> bool {
+    match e.get_ref() {
+        Some(inner) => inner.is::<PayloadTooLarge>(),
+        None => false,
+    }
+}
+
+fn payload_too_large() -> io::Error {
+    io::Error::other(PayloadTooLarge)
+}
+
+/// Parse chunk size line: `chunk-size [ chunk-ext ]`
+/// 
+/// Chunk extensions (`;name=value`) are ignored.
+fn parse_chunk_size(line: &[u8]) -> Result<u64, ParseError> {
+    let end = line.iter().position(|&b| b == b';').unwrap_or(line.len());
+    // whitespace is allowed before chunk-ext
+    let size = match std::str::from_utf8(&line[..end]) {
+        Ok(size) => size.trim_end_matches([' ', '\t']),
+        Err(_) => return Err(ParseError::BadChunk),
+    };
+    // 16 hex digits is the max of u64, leading zeros are not counted
+    let digits = size.trim_start_matches('0');
+    if size.is_empty() || digits.len() > 16 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::BadChunk)
+    }
+    u64::from_str_radix(size, 16).map_err(|_| ParseError::BadChunk)
+}
+
+/// Parse a trailer line, which is the same as a header line
+fn parse_trailer(line: &[u8]) -> Result<(String, String), ParseError> {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) if line.is_ascii() => line,
+        _ => return Err(ParseError::BadChunk),
+    };
+    let mut kv = line.splitn(2, ':');
+    match (kv.next(), kv.next()) {
+        (Some(k), Some(v)) if is_token(k) => Ok((k.to_string(), v.trim().to_string())),
+        _ => Err(ParseError::BadChunk),
+    }
+}
+
+/// Body framing state of the current request
+#[derive(Debug, PartialEq)]
+enum BodyState {
+    /// Bytes left in a `Content-Length` body
+    Length(u64),
+    /// Bytes left in current chunk, 0 means a chunk size line is expected
+    Chunked(u64),
+    /// Chunk data is read, CRLF after it is expected
+    ChunkEnd,
+    /// Last chunk is read, trailer fields are expected
+    Trailers,
+    /// Body exceeds the size limit, the rest of it can not be consumed
+    TooLarge,
+    /// Body has been consumed
+    Done,
+}
+
+/// Stateful request reader built on any byte stream
+pub struct RequestReader<S> {
+    stream: S,
+    /// Bytes received but not consumed yet
+    buffer: Vec<u8>,
+    /// Body state of the current request
+    body: BodyState,
+    /// Body bytes received in current request
+    body_received: u64,
+    /// Max body size of a request
+    max_body_size: u64,
+    /// Trailer fields of current chunked request
+    trailers: HeaderMap,
+}
+
+impl<S: Read> RequestReader<S> {
+    pub fn new(stream: S) -> Self {
+        RequestReader {
+            stream,
+            buffer: Vec::new(),
+            body: BodyState::Done,
+            body_received: 0,
+            max_body_size: u64::MAX,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    /// Set max body size of each request, larger body will be rejected
+    pub fn set_max_body_size(&mut self, size: u64) {
+        self.max_body_size = size;
+    }
+
+    /// Trailer fields of current request
+    /// 
+    /// Only chunked body has trailers, they are available after the whole body is read.
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    /// Bytes received but not parsed yet, e.g. the next pipelined request
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Read more bytes from stream into buffer
+    ///
+    /// Return the number of bytes read, 0 means EOF.
+    fn fill_buffer(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; READ_SIZE];
+        let size = self.stream.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..size]);
+        Ok(size)
+    }
+
+    /// Take `len` bytes from buffer, read from stream if necessary
+    fn take_exact(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        while self.buffer.len() < len {
+            if self.fill_buffer()? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        }
+        let rest = self.buffer.split_off(len);
+        Ok(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    /// Take a line (without line ending) from buffer, read from stream if necessary
+    fn take_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut searched = 0;
+        loop {
+            if let Some(pos) = self.buffer[searched..].iter().position(|&b| b == b'\n') {
+                let mut line = self.take_exact(searched + pos + 1)?;
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line)
+            }
+            if self.buffer.len() > BUFFER_SIZE {
+                return Err(ParseError::BadChunk.into())
+            }
+            searched = self.buffer.len();
+            if self.fill_buffer()? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        }
+    }
+
+    /// Read some body bytes, from buffer first, then from stream
+    fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = if self.buffer.is_empty() {
+            self.stream.read(buf)?
+        } else {
+            let size = buf.len().min(self.buffer.len());
+            buf[..size].copy_from_slice(&self.buffer[..size]);
+            self.buffer.drain(..size);
+            size
+        };
+        if size == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        }
+        Ok(size)
+    }
+
+    /// Read body of current request
+    ///
+    /// Chunked body is decoded here.
+    /// ref: https://tools.ietf.org/html/rfc7230#section-4.1
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.body {
+                BodyState::Done => return Ok(0),
+                BodyState::TooLarge => return Err(payload_too_large()),
+                BodyState::Length(0) => {
+                    self.body = BodyState::Done;
+                }
+                BodyState::Length(remaining) => {
+                    let len = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+                    let size = self.read_some(&mut buf[..len])?;
+                    self.body = BodyState::Length(remaining - size as u64);
+                    return Ok(size)
+                }
+                BodyState::Chunked(0) => {
+                    let size = parse_chunk_size(&self.take_line()?)?;
+                    if size == 0 {
+                        self.body = BodyState::Trailers;
+                        continue
+                    }
+                    self.body_received = self.body_received.saturating_add(size);
+                    if self.body_received > self.max_body_size {
+                        self.body = BodyState::TooLarge;
+                        continue
+                    }
+                    self.body = BodyState::Chunked(size);
+                }
+                BodyState::Chunked(remaining) => {
+                    let len = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+                    let size = self.read_some(&mut buf[..len])?;
+                    let remaining = remaining - size as u64;
+                    self.body = if remaining == 0 { BodyState::ChunkEnd } else { BodyState::Chunked(remaining) };
+                    return Ok(size)
+                }
+                BodyState::ChunkEnd => {
+                    if !self.take_line()?.is_empty() {
+                        return Err(ParseError::BadChunk.into())
+                    }
+                    self.body = BodyState::Chunked(0);
+                }
+                BodyState::Trailers => {
+                    self.read_trailers()?;
+                    self.body = BodyState::Done;
+                }
+            }
+        }
+    }
+
+    /// Read trailer fields after the last chunk, which end with an empty line
+    fn read_trailers(&mut self) -> io::Result<()> {
+        // trailers read before a `WouldBlock` are counted too
+        let mut size: usize = self.trailers.iter().map(|(k, v)| k.len() + v.len()).sum();
+        loop {
+            let line = self.take_line()?;
+            if line.is_empty() {
+                return Ok(())
+            }
+            size += line.len();
+            if size > BUFFER_SIZE {
+                return Err(ParseError::HeaderTooLarge.into())
+            }
+            let (k, v) = parse_trailer(&line)?;
+            self.trailers.append(k, v);
+        }
+    }
+
+    /// Body of current request as a `Read` stream
+    pub fn body(&mut self) -> RequestBody<'_, S> {
+        RequestBody { reader: self }
+    }
+
+    /// Consume the rest of current request body
+    fn skip_body(&mut self) -> io::Result<()> {
+        io::copy(&mut self.body(), &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Drop empty lines before a request line, which some clients send after a body
+    ///
+    /// ref: https://tools.ietf.org/html/rfc7230#section-3.5
+    fn skip_empty_lines(&mut self) {
+        let mut start = 0;
+        loop {
+            if self.buffer[start..].starts_with(b"\r\n") {
+                start += 2;
+            } else if self.buffer[start..].starts_with(b"\n") {
+                start += 1;
+            } else {
+                break
+            }
+        }
+        self.buffer.drain(..start);
+    }
+
+    /// Read the head of next request from stream
+    ///
+    /// Unread body of previous request is skipped.
+    /// Body of the new request can be read by `body()`.
+    ///
+    /// * Return `Ok(Some(head))` if a request is received.
+    /// * Return `Ok(None)` if stream is closed before a new request begins.
+    /// * Return `Err` if stream is broken or request is malformed,
+    ///   `ParseError::from_io` tells why the request is malformed.
+    ///   A malformed body of previous request has been answered, its error
+    ///   carries no `ParseError`, and connection should be closed.
+    pub fn read_request(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Err(e) = self.skip_body() {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Err(e)
+            }
+            return Err(io::Error::new(e.kind(), format!("unread body of previous request: {}", e)))
+        }
+
+        // accumulate until the head terminator is found
+        let (head_len, body_start) = loop {
+            self.skip_empty_lines();
+            if let Some(end) = find_head_end(&self.buffer) {
+                break end
+            }
+            if self.buffer.len() > BUFFER_SIZE {
+                return Err(ParseError::HeaderTooLarge.into())
+            }
+            if self.fill_buffer()? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None)
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        };
+        let mut head = self.take_exact(body_start)?;
+        head.truncate(head_len);
+
+        // find out how body is framed, framing headers are checked by parser
+        let (chunked, length) = {
+            let request = HttpRequest::from_parts(&head, Box::new(io::empty()))?;
+            (request.headers.is_chunked(), request.headers.content_length().unwrap_or(0))
+        };
+        self.body_received = 0;
+        self.trailers = HeaderMap::new();
+        self.body = if chunked {
+            BodyState::Chunked(0)
+        } else if length > self.max_body_size {
+            BodyState::TooLarge
+        } else {
+            BodyState::Length(length)
+        };
+        Ok(Some(head))
+    }
+}
+
+/// Body of the current request, borrowed from `RequestReader`
+pub struct RequestBody<'r, S> {
+    reader: &'r mut RequestReader<S>,
+}
+
+impl<S: Read> RequestBody<'_, S> {
+    /// Trailer fields, available after the whole body is read
+    pub fn trailers(&self) -> &HeaderMap {
+        self.reader.trailers()
+    }
+}
+
+impl<S: Read> Read for RequestBody<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read_body(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stream which returns at most `step` bytes per read, like a slow client
+    struct SlowStream<'t> {
+        data: &'t [u8],
+        step: usize,
+    }
+
+    impl Read for SlowStream<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.step.min(buf.len()).min(self.data.len());
+            buf[..size].copy_from_slice(&self.data[..size]);
+            self.data = &self.data[size..];
+            Ok(size)
+        }
+    }
+
+    /// Stream which returns `WouldBlock` between bytes, like a non-blocking socket
+    struct NonBlockingStream<'t> {
+        data: &'t [u8],
+        ready: bool,
+    }
+
+    impl Read for NonBlockingStream<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let size = SlowStream { data: self.data, step: 1 }.read(buf)?;
+            self.data = &self.data[size..];
+            Ok(size)
+        }
+    }
+
+    /// Call `f` again and again until it does not return `WouldBlock`
+    fn retry<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match f() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn read_body<S: Read>(reader: &mut RequestReader<S>) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        reader.body().read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn read_split_request() {
+        let raw = b"PUT /a.txt HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world";
+        let mut reader = RequestReader::new(SlowStream { data: raw, step: 3 });
+        let head = reader.read_request().unwrap().unwrap();
+        assert_eq!(head, &b"PUT /a.txt HTTP/1.1\r\nContent-Length: 11\r\n"[..]);
+        assert_eq!(read_body(&mut reader).unwrap(), b"hello world");
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_chunked_request() {
+        let raw = b"POST /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut reader = RequestReader::new(SlowStream { data: raw, step: 5 });
+        reader.read_request().unwrap().unwrap();
+        assert_eq!(read_body(&mut reader).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn read_chunk_extensions_and_trailers() {
+        let mut raw = b"POST /a.bin HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            7;name=\"v a\";x\r\nline1\r\n\r\n00A \t; ext\r\n".to_vec();
+        raw.extend_from_slice(&[0x00, 0xff, b'\n', b'\r', 0x80, 0x0d, 0x0a, 1, 2, 3]);
+        raw.extend_from_slice(b"\r\n0;last\r\nExpires: 0\r\nDigest: sha=abc\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        let mut reader = RequestReader::new(SlowStream { data: &raw, step: 4 });
+        reader.read_request().unwrap().unwrap();
+        let mut expected = b"line1\r\n".to_vec();
+        expected.extend_from_slice(&[0x00, 0xff, b'\n', b'\r', 0x80, 0x0d, 0x0a, 1, 2, 3]);
+        assert_eq!(read_body(&mut reader).unwrap(), expected);
+        assert_eq!(reader.trailers().get("expires"), Some("0"));
+        assert_eq!(reader.trailers().get("Digest"), Some("sha=abc"));
+        // next request is not damaged, and has no trailers
+        assert_eq!(reader.read_request().unwrap().unwrap(), &b"GET / HTTP/1.1\r\n"[..]);
+        assert!(reader.trailers().is_empty());
+    }
+
+    #[test]
+    fn resume_after_would_block() {
+        let raw = b"POST /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\nExpires: 0\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut reader = RequestReader::new(NonBlockingStream { data: raw, ready: false });
+        retry(|| reader.read_request()).unwrap().unwrap();
+        let mut body = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            match retry(|| reader.body().read(&mut buf)).unwrap() {
+                0 => break,
+                size => body.extend_from_slice(&buf[..size]),
+            }
+        }
+        assert_eq!(body, b"hello world");
+        assert_eq!(reader.trailers().get("Expires"), Some("0"));
+        assert_eq!(retry(|| reader.read_request()).unwrap().unwrap(), &b"GET / HTTP/1.1\r\n"[..]);
+    }
+
+    #[test]
+    fn skip_empty_lines_before_request() {
+        let raw = b"\r\nPOST /a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\r\n\nGET / HTTP/1.1\r\n\r\n\r\n";
+        let mut reader = RequestReader::new(SlowStream { data: raw, step: 1 });
+        assert_eq!(reader.read_request().unwrap().unwrap(), &b"POST /a.txt HTTP/1.1\r\nContent-Length: 5\r\n"[..]);
+        assert_eq!(read_body(&mut reader).unwrap(), b"hello");
+        assert_eq!(reader.read_request().unwrap().unwrap(), &b"GET / HTTP/1.1\r\n"[..]);
+        // empty line before EOF is not a request
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn reject_bad_chunk() {
+        let raw = b"POST /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello world\r\n0\r\n\r\n";
+        let mut reader = RequestReader::new(&raw[..]);
+        reader.read_request().unwrap().unwrap();
+        let e = read_body(&mut reader).unwrap_err();
+        assert_eq!(ParseError::from_io(&e), Some(&ParseError::BadChunk));
+
+        for bad in [&b"+5\r\nhello\r\n0\r\n\r\n"[..], b"\r\n", b"5 5\r\nhello\r\n", b"x\r\n", b"10000000000000000\r\n", b"0\r\nbad trailer\r\n\r\n"] {
+            let mut raw = b"POST /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            raw.extend_from_slice(bad);
+            let mut reader = RequestReader::new(&raw[..]);
+            reader.read_request().unwrap().unwrap();
+            let e = read_body(&mut reader).unwrap_err();
+            assert_eq!(ParseError::from_io(&e), Some(&ParseError::BadChunk));
+        }
+
+        let raw = b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n";
+        let e = RequestReader::new(&raw[..]).read_request().unwrap_err();
+        assert_eq!(ParseError::from_io(&e), Some(&ParseError::MalformedHeader));
+    }
+
+    #[test]
+    fn bad_unread_body() {
+        let raw = b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        let mut reader = RequestReader::new(&raw[..]);
+        reader.read_request().unwrap().unwrap();
+        // handler did not read the body, it is not a new malformed request
+        let e = reader.read_request().unwrap_err();
+        assert_eq!(ParseError::from_io(&e), None);
+    }
+
+    #[test]
+    fn reject_large_body() {
+        let raw = b"PUT /a.txt HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world";
+        let mut reader = RequestReader::new(&raw[..]);
+        reader.set_max_body_size(5);
+        reader.read_request().unwrap().unwrap();
+        assert!(is_payload_too_large(&read_body(&mut reader).unwrap_err()));
+
+        let raw = b"POST /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut reader = RequestReader::new(&raw[..]);
+        reader.set_max_body_size(5);
+        reader.read_request().unwrap().unwrap();
+        assert!(is_payload_too_large(&read_body(&mut reader).unwrap_err()));
+    }
+}
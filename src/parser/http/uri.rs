@@ -0,0 +1,165 @@
+//! Request-target parser
+//!
+//! Split request-target into path and query, decode percent-encoded bytes.
+//!
+//! ref: https://tools.ietf.org/html/rfc3986#section-2.1
+//! ref: https://url.spec.whatwg.org/#application/x-www-form-urlencoded
+
+use super::ParseError;
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode `%XX` in `input`
+///
+/// If `plus_as_space` is set, `+` is decoded as space (form encoding).
+///
+/// Return `None` if `input` contains an invalid `%` escape.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'%' => {
+                let high = hex_value(*bytes.get(pos + 1)?)?;
+                let low = hex_value(*bytes.get(pos + 2)?)?;
+                output.push(high << 4 | low);
+                pos += 3;
+            }
+            b'+' if plus_as_space => {
+                output.push(b' ');
+                pos += 1;
+            }
+            b => {
+                output.push(b);
+                pos += 1;
+            }
+        }
+    }
+    Some(output)
+}
+
+/// Decode `%XX` and `+` in a form value, invalid escapes are kept as is
+fn form_decode(input: &str) -> String {
+    match percent_decode(input, true) {
+        Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        None => input.replace('+', " "),
+    }
+}
+
+/// Parsed query string, a key may have more than one value
+///
+/// Pairs are kept in the order they appear.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    /// Parse `k1=v1&k2=v2` style string, also used by urlencoded form body
+    pub fn parse(input: &str) -> Self {
+        let pairs = input.split('&')
+            .filter(|i| !i.is_empty())
+            .map(|i| {
+                let mut kv = i.splitn(2, '=');
+                let k = kv.next().unwrap_or("");
+                let v = kv.next().unwrap_or("");
+                (form_decode(k), form_decode(v))
+            })
+            .collect();
+        Query { pairs }
+    }
+
+    /// Get the first value of `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Get all values of `key`
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs.iter().filter(move |(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// Split request-target into raw path and raw query
+///
+/// * origin-form: `/index.html?v=2`
+/// * absolute-form: `http://example.com/index.html?v=2`
+/// * asterisk-form: `*`
+///
+/// Fragment is removed.
+pub fn split_target(target: &str) -> (&str, &str) {
+    let target = target.split('#').next().unwrap_or("");
+    // absolute-form, remove scheme and authority
+    let target = match target.find("://") {
+        Some(pos) if !target.starts_with('/') => {
+            let rest = &target[pos + 3..];
+            match rest.find('/') {
+                Some(path_start) => &rest[path_start..],
+                None => "/",
+            }
+        }
+        _ => target,
+    };
+    let mut parts = target.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+    (path, query)
+}
+
+/// Parse request-target into decoded path and query
+pub fn parse_target(target: &str) -> Result<(String, Query), ParseError> {
+    let (raw_path, raw_query) = split_target(target);
+    if !raw_path.starts_with('/') && raw_path != "*" {
+        return Err(ParseError::MalformedUri)
+    }
+    let path = match percent_decode(raw_path, false) {
+        Some(bytes) => match String::from_utf8(bytes) {
+            Ok(path) => path,
+            Err(_) => return Err(ParseError::MalformedUri),
+        },
+        None => return Err(ParseError::MalformedUri),
+    };
+    Ok((path, Query::parse(raw_query)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_path_and_query() {
+        let (path, query) = parse_target("/my%20dir/index.html?v=2&tag=a+b&tag=%E4%BD%A0#top").unwrap();
+        assert_eq!(path, "/my dir/index.html");
+        assert_eq!(query.get("v"), Some("2"));
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), ["a b", "你"]);
+        assert_eq!(query.len(), 3);
+
+        let (path, query) = parse_target("http://localhost:7878/a+b.txt").unwrap();
+        assert_eq!(path, "/a+b.txt");
+        assert!(query.is_empty());
+
+        assert_eq!(parse_target("/bad%2"), Err(ParseError::MalformedUri));
+        assert_eq!(parse_target("/bad%ff"), Err(ParseError::MalformedUri));
+        assert_eq!(parse_target("index.html"), Err(ParseError::MalformedUri));
+    }
+}
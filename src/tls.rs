@@ -0,0 +1,646 @@
+//! TLS config
+//!
+//! Build `SslAcceptor` from `TlsConfig`, every failure is reported as `TlsError`
+//! so a wrong config stops the server with a clear message at startup.
+//!
+//! Each virtual host can have its own certificate, it is selected by the
+//! server name (SNI) sent by client. The default certificate is used if
+//! client sends no server name or an unknown one.
+//!
+//! Client certificate can be required, it must be issued by `client_ca_file`.
+//!
+//! Certificate files are watched, acceptor is rebuilt when they change.
+//! New connections use the new acceptor, existing ones keep the old one.
+//!
+//! In development mode, a self-signed certificate is generated in memory,
+//! no key pair has to be shipped with rhttp.
+
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{NameType, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod, SslRef, SslVerifyMode, SslVersion};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509, X509Name, X509NameBuilder, X509VerifyResult};
+
+/// Lowest TLS version accepted
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls1_0,
+    #[serde(rename = "1.1")]
+    Tls1_1,
+    #[serde(rename = "1.2")]
+    Tls1_2,
+    #[serde(rename = "1.3")]
+    Tls1_3,
+}
+
+impl From<TlsVersion> for SslVersion {
+    fn from(v: TlsVersion) -> Self {
+        match v {
+            TlsVersion::Tls1_0 => SslVersion::TLS1,
+            TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+            TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+            TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// Mozilla server side TLS profile
+///
+/// ref: https://wiki.mozilla.org/Security/Server_Side_TLS
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsProfile {
+    /// TLS 1.3 only
+    Modern,
+    /// TLS 1.2 and 1.3, compatible with most clients
+    Intermediate,
+}
+
+/// How client certificate is verified
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientVerify {
+    /// Client certificate is not requested
+    None,
+    /// Client certificate is requested, client without one is accepted
+    Optional,
+    /// Client without a valid certificate is rejected in handshake
+    Required,
+}
+
+/// Certificate of some virtual hosts
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TlsHost {
+    /// host names using this certificate, `*.example.com` matches one label
+    pub hostnames: Vec<String>,
+    /// certificate chain file (PEM)
+    pub cert_file: String,
+    /// private key file (PEM)
+    pub key_file: String,
+}
+
+/// TLS config, used by all HTTPS listeners
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// certificate chain file (PEM)
+    pub cert_file: String,
+    /// private key file (PEM), should not be placed in root dir
+    pub key_file: String,
+    /// cipher profile
+    pub profile: TlsProfile,
+    /// lowest TLS version, `None` means the one of profile
+    pub min_version: Option<TlsVersion>,
+    /// OpenSSL cipher list for TLS 1.2 and below, overrides profile if not empty
+    pub cipher_list: String,
+    /// TLS 1.3 ciphersuites, overrides profile if not empty
+    pub ciphersuites: String,
+    /// how client certificate is verified
+    pub client_verify: ClientVerify,
+    /// trusted CA bundle (PEM) for client certificates
+    pub client_ca_file: String,
+    /// check certificate files for changes every `reload_interval` secs, 0 disables reload
+    pub reload_interval: u64,
+    /// close connection if handshake is not done in `handshake_timeout` secs, 0 means no limit
+    pub handshake_timeout: u64,
+    /// answer plain HTTP request on HTTPS port with a redirect to https, instead of closing it
+    pub redirect_plain_http: bool,
+    /// host names and IPs of the self-signed certificate in development mode
+    pub dev_hostnames: Vec<String>,
+    /// write the self-signed certificate (PEM) to this file if not empty, so clients can trust it
+    pub dev_cert_file: String,
+    /// certificates selected by SNI, `cert_file` and `key_file` is the default one
+    pub hosts: Vec<TlsHost>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self { Self {
+        cert_file: String::new(),
+        key_file: String::new(),
+        profile: TlsProfile::Intermediate,
+        min_version: None,
+        cipher_list: String::new(),
+        ciphersuites: String::new(),
+        client_verify: ClientVerify::None,
+        client_ca_file: String::new(),
+        reload_interval: 10,
+        handshake_timeout: 10,
+        redirect_plain_http: false,
+        dev_hostnames: vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()],
+        dev_cert_file: String::new(),
+        hosts: Vec::new(),
+    } }
+}
+
+/// Reason why TLS can not be set up
+#[derive(Debug)]
+pub enum TlsError {
+    /// A required config field is empty
+    Missing(&'static str),
+    /// A config value is rejected by OpenSSL
+    Invalid(&'static str, ErrorStack),
+    /// Certificate or key file can not be loaded
+    File(String, ErrorStack),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Missing(field) => write!(f, "TLS config `{}` is not set", field),
+            TlsError::Invalid(field, e) => write!(f, "invalid TLS config `{}`: {}", field, e),
+            TlsError::File(path, e) => write!(f, "fail to load `{}`: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Create acceptor builder with profile, versions and ciphers of `cfg`
+///
+/// Certificate is not loaded.
+pub fn acceptor_builder(cfg: &TlsConfig) -> Result<SslAcceptorBuilder, TlsError> {
+    let builder = match cfg.profile {
+        TlsProfile::Modern => SslAcceptor::mozilla_modern_v5(SslMethod::tls()),
+        TlsProfile::Intermediate => SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()),
+    };
+    let mut builder = builder.map_err(|e| TlsError::Invalid("profile", e))?;
+    if let Some(version) = cfg.min_version {
+        builder.set_min_proto_version(Some(version.into())).map_err(|e| TlsError::Invalid("min_version", e))?;
+    }
+    if !cfg.cipher_list.is_empty() {
+        builder.set_cipher_list(&cfg.cipher_list).map_err(|e| TlsError::Invalid("cipher_list", e))?;
+    }
+    if !cfg.ciphersuites.is_empty() {
+        builder.set_ciphersuites(&cfg.ciphersuites).map_err(|e| TlsError::Invalid("ciphersuites", e))?;
+    }
+    // set on every context, for SNI may switch to the context of a virtual host
+    if cfg.client_verify != ClientVerify::None {
+        if cfg.client_ca_file.is_empty() {
+            return Err(TlsError::Missing("client_ca_file"))
+        }
+        let ca_file = &cfg.client_ca_file;
+        builder.set_ca_file(ca_file).map_err(|e| TlsError::File(ca_file.to_string(), e))?;
+        // tell client which CAs are trusted
+        let ca_names = X509Name::load_client_ca_file(ca_file).map_err(|e| TlsError::File(ca_file.to_string(), e))?;
+        builder.set_client_ca_list(ca_names);
+        builder.set_verify(match cfg.client_verify {
+            ClientVerify::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            _ => SslVerifyMode::PEER,
+        });
+    }
+    Ok(builder)
+}
+
+/// Load certificate chain and private key into `builder`
+pub fn load_certificate(builder: &mut SslAcceptorBuilder, cert_file: &str, key_file: &str) -> Result<(), TlsError> {
+    if cert_file.is_empty() {
+        return Err(TlsError::Missing("cert_file"))
+    }
+    if key_file.is_empty() {
+        return Err(TlsError::Missing("key_file"))
+    }
+    builder.set_certificate_chain_file(cert_file).map_err(|e| TlsError::File(cert_file.to_string(), e))?;
+    builder.set_private_key_file(key_file, SslFiletype::PEM).map_err(|e| TlsError::File(key_file.to_string(), e))?;
+    builder.check_private_key().map_err(|e| TlsError::File(key_file.to_string(), e))
+}
+
+/// Check if server name `name` matches `pattern`, case-insensitive
+///
+/// `*.example.com` matches `www.example.com`, but not `example.com` or `a.b.example.com`.
+pub fn match_hostname(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match name.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+            None => false,
+        },
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Build acceptor used by HTTPS listeners
+pub fn build_acceptor(cfg: &TlsConfig) -> Result<SslAcceptor, TlsError> {
+    let mut builder = acceptor_builder(cfg)?;
+    load_certificate(&mut builder, &cfg.cert_file, &cfg.key_file)?;
+
+    // contexts of virtual hosts, in the order of config
+    let mut contexts: Vec<(Vec<String>, SslContext)> = Vec::new();
+    for host in &cfg.hosts {
+        if host.hostnames.is_empty() {
+            return Err(TlsError::Missing("hosts.hostnames"))
+        }
+        let mut host_builder = acceptor_builder(cfg)?;
+        load_certificate(&mut host_builder, &host.cert_file, &host.key_file)?;
+        contexts.push((host.hostnames.clone(), host_builder.build().into_context()));
+    }
+    if !contexts.is_empty() {
+        // switch to the certificate of requested host during handshake,
+        // the default certificate is kept if no host matches
+        builder.set_servername_callback(move |ssl, _alert| {
+            let name = match ssl.servername(NameType::HOST_NAME) {
+                Some(name) => name.to_string(),
+                None => return Ok(()),
+            };
+            let matched = contexts.iter().find(|(hostnames, _)| hostnames.iter().any(|i| match_hostname(i, &name)));
+            if let Some((_, context)) = matched {
+                // fails only if context is broken, keep the default certificate then
+                let _ = ssl.set_ssl_context(context);
+            }
+            Ok(())
+        });
+    }
+    Ok(builder.build())
+}
+
+/// Generate a self-signed certificate for `hostnames`, valid for `days`
+///
+/// IP addresses are added as IP SAN, others as DNS SAN, the first one is used as CN.
+pub fn generate_self_signed(hostnames: &[String], days: u32) -> Result<(X509, PKey<Private>), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", hostnames.first().map(|i| i.as_str()).unwrap_or("localhost"))?;
+    name.append_entry_by_text("O", "rhttp development")?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    let serial = serial.to_asn1_integer()?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    if !hostnames.is_empty() {
+        let mut san = SubjectAlternativeName::new();
+        for host in hostnames {
+            match host.parse::<IpAddr>() {
+                Ok(_) => san.ip(host),
+                Err(_) => san.dns(host),
+            };
+        }
+        let san = san.build(&cert.x509v3_context(None, None))?;
+        cert.append_extension(san)?;
+    }
+    cert.sign(&key, MessageDigest::sha256())?;
+    Ok((cert.build(), key))
+}
+
+/// Build acceptor with a self-signed certificate of `dev_hostnames`
+///
+/// Certificate is written to `dev_cert_file` if it is set.
+pub fn build_dev_acceptor(cfg: &TlsConfig) -> Result<SslAcceptor, TlsError> {
+    let (cert, key) = generate_self_signed(&cfg.dev_hostnames, 30).map_err(|e| TlsError::Invalid("dev_hostnames", e))?;
+    let mut builder = acceptor_builder(cfg)?;
+    builder.set_certificate(&cert).map_err(|e| TlsError::Invalid("dev_hostnames", e))?;
+    builder.set_private_key(&key).map_err(|e| TlsError::Invalid("dev_hostnames", e))?;
+    if !cfg.dev_cert_file.is_empty() {
+        let pem = cert.to_pem().map_err(|e| TlsError::Invalid("dev_hostnames", e))?;
+        if let Err(e) = fs::write(&cfg.dev_cert_file, pem) {
+            println!("fail to write certificate to {}: {}", cfg.dev_cert_file, e);
+        }
+    }
+    Ok(builder.build())
+}
+
+/// Acceptor shared by listeners, can be replaced while server is running
+#[derive(Clone)]
+pub struct SharedAcceptor {
+    inner: Arc<RwLock<Arc<SslAcceptor>>>,
+}
+
+impl SharedAcceptor {
+    pub fn new(acceptor: SslAcceptor) -> Self {
+        SharedAcceptor { inner: Arc::new(RwLock::new(Arc::new(acceptor))) }
+    }
+
+    /// Acceptor for a new connection
+    pub fn current(&self) -> Arc<SslAcceptor> {
+        // acceptor is replaced as a whole, a poisoned lock still holds a valid one
+        match self.inner.read() {
+            Ok(acceptor) => acceptor.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Replace acceptor, connections accepted before are not affected
+    pub fn replace(&self, acceptor: SslAcceptor) {
+        let mut current = match self.inner.write() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *current = Arc::new(acceptor);
+    }
+}
+
+/// Modified time of all files used by `cfg`
+fn modified_times(cfg: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let mut files = vec![&cfg.cert_file, &cfg.key_file, &cfg.client_ca_file];
+    for host in &cfg.hosts {
+        files.push(&host.cert_file);
+        files.push(&host.key_file);
+    }
+    files.iter()
+        .map(|file| fs::metadata(file).and_then(|i| i.modified()).ok())
+        .collect()
+}
+
+/// Rebuild `shared` acceptor if files of `cfg` are modified after `last`
+///
+/// `last` is updated to the current modified times.
+/// If new files are broken (e.g. only half of them are written), the old
+/// acceptor is kept and rebuild is tried again on the next change.
+///
+/// Return true if acceptor is replaced.
+fn reload_if_changed(shared: &SharedAcceptor, cfg: &TlsConfig, last: &mut Vec<Option<SystemTime>>) -> bool {
+    let now = modified_times(cfg);
+    if now == *last {
+        return false
+    }
+    *last = now;
+    match build_acceptor(cfg) {
+        Ok(acceptor) => {
+            shared.replace(acceptor);
+            println!("TLS certificates reloaded");
+            true
+        }
+        Err(e) => {
+            println!("fail to reload TLS certificates, keep the old ones: {}", e);
+            false
+        }
+    }
+}
+
+/// Watch certificate files of `cfg`, rebuild `shared` acceptor when they change
+pub fn watch_certificates(shared: SharedAcceptor, cfg: TlsConfig) -> Option<thread::JoinHandle<()>> {
+    if cfg.reload_interval == 0 {
+        return None
+    }
+    let interval = Duration::from_secs(cfg.reload_interval);
+    Some(thread::spawn(move || {
+        let mut last = modified_times(&cfg);
+        loop {
+            thread::sleep(interval);
+            reload_if_changed(&shared, &cfg, &mut last);
+        }
+    }))
+}
+
+/// Subject of verified client certificate, e.g. `CN=alice,O=example`
+///
+/// Return `None` if client sent no certificate.
+pub fn client_subject(ssl: &SslRef) -> Option<String> {
+    let cert = ssl.peer_certificate()?;
+    if ssl.verify_result() != X509VerifyResult::OK {
+        return None
+    }
+    let entries: Vec<String> = cert.subject_name().entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().as_utf8().map(|i| i.to_string()).unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect();
+    Some(entries.join(","))
+}
+
+/// Check if `file` is inside `root_dir`, where everyone can download it
+pub fn is_served(file: &str, root_dir: &str) -> bool {
+    match (Path::new(file).canonicalize(), Path::new(root_dir).canonicalize()) {
+        (Ok(file), Ok(root)) => file.starts_with(root),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+
+    /// TLS config with a self-signed test certificate of test2020.com
+    fn test_tls_config() -> TlsConfig {
+        let (cert_file, key_file) = write_test_certificate("test2020.com");
+        TlsConfig { cert_file, key_file, ..Default::default() }
+    }
+
+    #[test]
+    fn build_from_config() {
+        assert!(build_acceptor(&test_tls_config()).is_ok());
+        let cfg = TlsConfig {
+            profile: TlsProfile::Modern,
+            min_version: Some(TlsVersion::Tls1_3),
+            ..test_tls_config()
+        };
+        assert!(build_acceptor(&cfg).is_ok());
+        let root_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/page");
+        assert!(!is_served(&cfg.key_file, root_dir));
+        assert!(is_served(concat!(env!("CARGO_MANIFEST_DIR"), "/page/hello.html"), root_dir));
+    }
+
+    #[test]
+    fn select_host_by_name() {
+        assert!(match_hostname("Example.com", "example.COM."));
+        assert!(match_hostname("*.example.com", "www.example.com"));
+        assert!(!match_hostname("*.example.com", "example.com"));
+        assert!(!match_hostname("*.example.com", "a.b.example.com"));
+        assert!(!match_hostname("example.com", "www.example.com"));
+
+        let test_cfg = test_tls_config();
+        let cfg = TlsConfig {
+            hosts: vec![TlsHost {
+                hostnames: vec!["test2020.com".to_string(), "*.test2020.com".to_string()],
+                cert_file: test_cfg.cert_file.clone(),
+                key_file: test_cfg.key_file.clone(),
+            }],
+            ..test_tls_config()
+        };
+        assert!(build_acceptor(&cfg).is_ok());
+        let cfg = TlsConfig {
+            hosts: vec![TlsHost { hostnames: vec!["a.com".to_string()], ..Default::default() }],
+            ..test_tls_config()
+        };
+        assert_eq!(build_acceptor(&cfg).err().unwrap().to_string(), "TLS config `cert_file` is not set");
+    }
+
+    /// Write a self-signed certificate of `cn` into temp dir, return (cert_file, key_file)
+    fn write_test_certificate(cn: &str) -> (String, String) {
+        let (cert, key) = generate_self_signed(&[cn.to_string()], 1).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("rhttp-{}-{}.crt", cn, std::process::id()));
+        let key_file = dir.join(format!("rhttp-{}-{}.key", cn, std::process::id()));
+        fs::write(&cert_file, cert.to_pem().unwrap()).unwrap();
+        fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_file.to_str().unwrap().to_string(), key_file.to_str().unwrap().to_string())
+    }
+
+    /// Handshake with `acceptor` using client certificate, return client subject seen by server
+    fn handshake_with_client_cert(acceptor: SslAcceptor, client: Option<(&str, &str)>) -> Result<Option<String>, ()> {
+        use openssl::ssl::{SslConnector, SslVerifyMode};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).map_err(|_| ())?;
+            let subject = client_subject(stream.ssl());
+            // TLS 1.3 client learns rejection after handshake, send a byte to confirm
+            stream.write_all(b"1").map_err(|_| ())?;
+            Ok(subject)
+        });
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some((cert_file, key_file)) = client {
+            connector.set_certificate_chain_file(cert_file).unwrap();
+            connector.set_private_key_file(key_file, SslFiletype::PEM).unwrap();
+        }
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        if let Ok(mut stream) = connector.build().connect("localhost", stream) {
+            let _ = stream.read(&mut [0; 1]);
+        }
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn client_certificate_verify() {
+        let (server_cert, server_key) = write_test_certificate("server.test");
+        let (client_cert, client_key) = write_test_certificate("alice");
+        let (other_cert, other_key) = write_test_certificate("mallory");
+        let cfg = TlsConfig {
+            cert_file: server_cert,
+            key_file: server_key,
+            client_verify: ClientVerify::Required,
+            client_ca_file: client_cert.clone(),
+            ..Default::default()
+        };
+        let client = Some((client_cert.as_str(), client_key.as_str()));
+        assert_eq!(handshake_with_client_cert(build_acceptor(&cfg).unwrap(), client), Ok(Some("CN=alice,O=rhttp development".to_string())));
+        assert_eq!(handshake_with_client_cert(build_acceptor(&cfg).unwrap(), None), Err(()));
+        let other = Some((other_cert.as_str(), other_key.as_str()));
+        assert_eq!(handshake_with_client_cert(build_acceptor(&cfg).unwrap(), other), Err(()));
+
+        let cfg = TlsConfig { client_verify: ClientVerify::Optional, ..cfg };
+        assert_eq!(handshake_with_client_cert(build_acceptor(&cfg).unwrap(), None), Ok(None));
+        assert_eq!(handshake_with_client_cert(build_acceptor(&cfg).unwrap(), client), Ok(Some("CN=alice,O=rhttp development".to_string())));
+
+        let cfg = TlsConfig { client_ca_file: String::new(), ..cfg };
+        assert_eq!(build_acceptor(&cfg).err().unwrap().to_string(), "TLS config `client_ca_file` is not set");
+    }
+
+    /// Handshake with `acceptor` using server name `sni`, return CN of server certificate
+    fn server_cn(acceptor: SslAcceptor, sni: &str) -> String {
+        use openssl::nid::Nid;
+        use openssl::ssl::{SslConnector, SslVerifyMode};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = acceptor.accept(stream);
+        });
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let stream = connector.build().connect(sni, stream).unwrap();
+        let cert = stream.ssl().peer_certificate().unwrap();
+        let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap().data().as_utf8().unwrap().to_string();
+        drop(stream);
+        server.join().unwrap();
+        cn
+    }
+
+    #[test]
+    fn sni_handshake() {
+        let (default_cert, default_key) = write_test_certificate("default.test");
+        let (host_cert, host_key) = write_test_certificate("host.test");
+        let cfg = TlsConfig {
+            cert_file: default_cert,
+            key_file: default_key,
+            hosts: vec![TlsHost { hostnames: vec!["*.host.test".to_string()], cert_file: host_cert, key_file: host_key }],
+            ..Default::default()
+        };
+        assert_eq!(server_cn(build_acceptor(&cfg).unwrap(), "www.host.test"), "host.test");
+        assert_eq!(server_cn(build_acceptor(&cfg).unwrap(), "other.test"), "default.test");
+    }
+
+    #[test]
+    fn reload_changed_certificate() {
+        let (cert_file, key_file) = write_test_certificate("old.test");
+        let (new_cert, new_key) = write_test_certificate("new.test");
+        let cfg = TlsConfig { cert_file: cert_file.clone(), key_file: key_file.clone(), reload_interval: 1, ..Default::default() };
+        let shared = SharedAcceptor::new(build_acceptor(&cfg).unwrap());
+        let old = shared.current();
+        let mut last = modified_times(&cfg);
+        assert!(!reload_if_changed(&shared, &cfg, &mut last));
+        // mtime may have a coarse granularity, set it instead of waiting
+        let touch = |file: &str, secs: u64| {
+            let file = fs::File::options().write(true).open(file).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(secs)).unwrap();
+        };
+
+        // broken files are ignored
+        fs::write(&cert_file, "broken").unwrap();
+        touch(&cert_file, 10);
+        assert!(!reload_if_changed(&shared, &cfg, &mut last));
+        assert!(Arc::ptr_eq(&old, &shared.current()));
+        assert_eq!(server_cn((*shared.current()).clone(), "old.test"), "old.test");
+
+        fs::copy(&new_key, &key_file).unwrap();
+        fs::copy(&new_cert, &cert_file).unwrap();
+        touch(&key_file, 20);
+        touch(&cert_file, 20);
+        assert!(reload_if_changed(&shared, &cfg, &mut last));
+        assert_eq!(server_cn((*shared.current()).clone(), "new.test"), "new.test");
+        // acceptor held by old connections still works
+        assert_eq!(server_cn((*old).clone(), "old.test"), "old.test");
+    }
+
+    #[test]
+    fn dev_certificate() {
+        let cert_file = std::env::temp_dir().join(format!("rhttp-dev-{}.crt", std::process::id()));
+        let cfg = TlsConfig {
+            dev_hostnames: vec!["dev.test".to_string(), "10.0.0.1".to_string()],
+            dev_cert_file: cert_file.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        assert_eq!(server_cn(build_dev_acceptor(&cfg).unwrap(), "dev.test"), "dev.test");
+        let cert = X509::from_pem(&fs::read(&cert_file).unwrap()).unwrap();
+        let san = cert.subject_alt_names().unwrap();
+        assert_eq!(san.iter().filter_map(|i| i.dnsname()).collect::<Vec<_>>(), ["dev.test"]);
+        assert_eq!(san.iter().filter_map(|i| i.ipaddress()).collect::<Vec<_>>(), [&[10u8, 0, 0, 1][..]]);
+    }
+
+    #[test]
+    fn report_bad_config() {
+        let err = build_acceptor(&TlsConfig::default()).err().unwrap();
+        assert_eq!(err.to_string(), "TLS config `cert_file` is not set");
+
+        let cfg = TlsConfig { key_file: "/nonexistent/key.pem".to_string(), ..test_tls_config() };
+        match build_acceptor(&cfg) {
+            Err(TlsError::File(path, _)) => assert_eq!(path, "/nonexistent/key.pem"),
+            _ => panic!("missing key file is accepted"),
+        }
+
+        let cfg = TlsConfig { cipher_list: "NO-SUCH-CIPHER".to_string(), ..test_tls_config() };
+        assert!(matches!(build_acceptor(&cfg), Err(TlsError::Invalid("cipher_list", _))));
+    }
+}
@@ -0,0 +1,614 @@
+//! Async server on tokio
+//!
+//! Built with the `tokio-backend` feature, selected by `io_mode = "async"`.
+//! Sockets are read and written asynchronously, TLS is done by tokio-openssl.
+//!
+//! Requests are parsed by the same `RequestReader`, fed with bytes received
+//! asynchronously, and answered by the same handlers in `parser::http::method`.
+//! Handlers read and write files, so they run on blocking threads of tokio,
+//! at most `thread_number` of them.
+//!
+//! Request body is passed to its handler through a bounded channel while it
+//! is received, so an upload never waits in memory. File body of response is
+//! sent in pieces.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::future::{poll_fn, Future};
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use openssl::ssl::{Ssl, SslAcceptor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::mpsc;
+use tokio_openssl::SslStream;
+
+use super::{Config, LINGER_SIZE, LINGER_TIME, listener, prepare_response, tls, write_response};
+use super::parser::http::{Body, HeaderMap, HttpRequest, HttpResponse, ParseError};
+use super::parser::http::reader::RequestReader;
+use super::parser::http::method::utils::ChunkedWriter;
+use listener::{FirstByte, ListenerKind, check_first_byte, peer_name};
+use tls::SharedAcceptor;
+
+/// Size of each read from socket
+const READ_SIZE: usize = 8192;
+/// Number of body pieces received ahead of the handler
+const BODY_QUEUE: usize = 4;
+
+/// Bytes received from socket, waiting to be parsed by `RequestReader`
+///
+/// Reading an empty inbox returns `WouldBlock`, more bytes should be received then.
+#[derive(Default)]
+struct Inbox {
+    data: VecDeque<u8>,
+    /// Client closed the connection, no more bytes will come
+    eof: bool,
+}
+
+impl Read for Inbox {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.data.is_empty() && !self.eof {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        self.data.read(buf)
+    }
+}
+
+type Reader = RequestReader<Inbox>;
+
+/// Receive more bytes for `reader`, wait at most `timeout` if it is set
+async fn receive<S: AsyncRead + Unpin>(stream: &mut S, reader: &mut Reader, timeout: Option<Duration>) -> io::Result<()> {
+    let mut buf = [0u8; READ_SIZE];
+    let size = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, stream.read(&mut buf)).await {
+            Ok(size) => size?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        },
+        None => stream.read(&mut buf).await?,
+    };
+    let inbox = reader.get_mut();
+    if size == 0 {
+        inbox.eof = true;
+    } else {
+        inbox.data.extend(&buf[..size]);
+    }
+    Ok(())
+}
+
+/// Response ready to be sent asynchronously
+struct Prepared {
+    /// Head, or the whole response if body is in memory
+    output: Vec<u8>,
+    /// File body with its length, sent after `output`
+    file: Option<(fs::File, u64)>,
+    chunked: bool,
+    /// Trailers sent after chunked file body
+    trailers: HeaderMap,
+    keep_alive: bool,
+}
+
+/// Fill connection related headers like `send_response` does, then generate bytes to send
+///
+/// File body is kept as file, other bodies are written into memory.
+fn prepare(mut response: HttpResponse, keep_alive: bool, allow_chunked: bool, cfg: &Config) -> Prepared {
+    let (keep_alive, chunked) = prepare_response(&mut response, keep_alive, allow_chunked, cfg);
+    match std::mem::take(&mut response.body) {
+        Body::File(file, len) => {
+            let head = response.generate_head_string();
+            println!("resp content head:\n{}\n", head);
+            return Prepared {
+                output: head.into_bytes(),
+                file: Some((file, len)),
+                chunked,
+                trailers: std::mem::take(&mut response.trailers),
+                keep_alive,
+            }
+        }
+        body => response.body = body,
+    }
+    let mut output = Vec::new();
+    // writing to memory only fails if body stream fails
+    let keep_alive = match write_response(&mut output, response, chunked, cfg) {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            println!("fail to generate response: {}, close TCP link.", e);
+            false
+        }
+    };
+    Prepared { output, file: None, chunked, trailers: HeaderMap::new(), keep_alive }
+}
+
+/// Write `prepared` to stream
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, prepared: Prepared, cfg: &Config) -> io::Result<()> {
+    stream.write_all(&prepared.output).await?;
+    if let Some((file, len)) = prepared.file {
+        let mut file = tokio::fs::File::from_std(file).take(len);
+        let mut buf = vec![0u8; cfg.chunk_size.clamp(1, READ_SIZE)];
+        let mut writer = if prepared.chunked { Some(ChunkedWriter::new(Vec::new(), cfg.chunk_size)) } else { None };
+        let mut sent = 0;
+        loop {
+            let size = file.read(&mut buf).await?;
+            if size == 0 {
+                break
+            }
+            sent += size as u64;
+            match &mut writer {
+                Some(writer) => {
+                    // encode in memory, chunks are sent as soon as they are complete
+                    writer.write_all(&buf[..size])?;
+                    stream.write_all(writer.get_mut()).await?;
+                    writer.get_mut().clear();
+                }
+                None => stream.write_all(&buf[..size]).await?,
+            }
+        }
+        if sent < len {
+            // file was truncated after Content-Length is sent
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        }
+        if let Some(writer) = writer {
+            stream.write_all(&writer.finish(&prepared.trailers)?).await?;
+        }
+    }
+    stream.flush().await
+}
+
+/// How long to wait for a started request, `None` means never time out
+fn request_timeout(cfg: &Config) -> Option<Duration> {
+    match cfg.request_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Read the head of next request
+///
+/// Keep-alive `timeout` applies while an answered connection is idle,
+/// `request_timeout` applies to the first request and to a started head.
+/// Malformed request is answered here. Return `None` if connection should be closed.
+async fn read_head<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, reader: &mut Reader, first: bool, cfg: &Config) -> Option<Vec<u8>> {
+    loop {
+        let result = reader.read_request();
+        match result {
+            Ok(Some(head)) => return Some(head),
+            // client closed TCP link
+            Ok(None) => return None,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let idle = !first && reader.buffered().iter().all(|&b| b == b'\r' || b == b'\n');
+                let timeout = if idle { Some(Duration::from_secs(cfg.timeout as u64)) } else { request_timeout(cfg) };
+                if let Err(e) = receive(stream, reader, timeout).await {
+                    println!("fail to read request: {}, close TCP link.", e);
+                    return None
+                }
+            }
+            Err(e) => {
+                match ParseError::from_io(&e) {
+                    Some(parse_error) => {
+                        // tell client why its request is rejected, then close TCP link
+                        println!("reject malformed request: {}", parse_error);
+                        let prepared = prepare(HttpResponse::from_parse_error(parse_error), false, false, cfg);
+                        if send(stream, prepared, cfg).await.is_ok() {
+                            linger(stream).await;
+                        }
+                    }
+                    None => println!("fail to read request: {}, close TCP link.", e),
+                }
+                return None
+            }
+        }
+    }
+}
+
+/// Async version of `linger`, close a rejected connection gracefully
+async fn linger<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+    if stream.shutdown().await.is_err() {
+        return
+    }
+    let draining = async {
+        let mut buf = [0u8; READ_SIZE];
+        let mut drained = 0;
+        while drained < LINGER_SIZE {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(size) => drained += size,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(LINGER_TIME, draining).await;
+}
+
+/// Request body given to handler, fed by `pump_body`
+///
+/// It ends when the sender is dropped, errors of receiving are passed on.
+struct BodyReceiver {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    piece: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BodyReceiver {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.piece.len() {
+            // called in a blocking thread of tokio
+            match self.rx.blocking_recv() {
+                Some(Ok(piece)) => {
+                    self.piece = piece;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.piece.len() - self.pos);
+        buf[..size].copy_from_slice(&self.piece[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+/// Receive body of current request and send it to handler piece by piece
+///
+/// Stop early if handler drops the body, the rest is skipped by the next `read_request`.
+/// Return false if connection is broken or timed out, it is closed without response.
+async fn pump_body<S: AsyncRead + Unpin>(stream: &mut S, reader: &mut Reader, body_tx: mpsc::Sender<io::Result<Vec<u8>>>, cfg: &Config) -> bool {
+    let timeout = request_timeout(cfg);
+    loop {
+        let mut piece = vec![0u8; READ_SIZE];
+        let result = reader.body().read(&mut piece);
+        match result {
+            Ok(0) => return true,
+            Ok(size) => {
+                piece.truncate(size);
+                if body_tx.send(Ok(piece)).await.is_err() {
+                    return true
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // handler may drop the body while waiting for client
+                let mut receiving = pin!(receive(stream, reader, timeout));
+                let mut closed = pin!(body_tx.closed());
+                let received = poll_fn(|cx| match closed.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(None),
+                    Poll::Pending => receiving.as_mut().poll(cx).map(Some),
+                }).await;
+                let received = match received {
+                    Some(received) => received,
+                    None => return true,
+                };
+                if let Err(e) = received {
+                    println!("fail to read request body: {}, close TCP link.", e);
+                    let _ = body_tx.send(Err(e)).await;
+                    return false
+                }
+            }
+            // malformed or too large body, handler tells client why
+            Err(e) => {
+                let _ = body_tx.send(Err(e)).await;
+                return true
+            }
+        }
+    }
+}
+
+/// Generate response of a received request, like `handle_request` does
+///
+/// Return `None` if connection should be closed without response.
+fn respond(head: &[u8], body: BodyReceiver, client_subject: Option<String>, cfg: &Config) -> Option<Prepared> {
+    // request head is ASCII, log it lossily
+    println!("Raw request head:\n{}", String::from_utf8_lossy(head));
+    let mut request = match HttpRequest::from_parts(head, Box::new(body)) {
+        Ok(request) => request,
+        Err(parse_error) => return Some(prepare(HttpResponse::from_parse_error(&parse_error), false, false, cfg)),
+    };
+    request.client_subject = client_subject;
+    let keep_alive = request.keep_alive();
+    // HTTP/1.0 client does not understand chunked body
+    let allow_chunked = request.version == "HTTP/1.1";
+    let response = HttpResponse::new(&mut request, cfg)?;
+    Some(prepare(response, keep_alive, allow_chunked, cfg))
+}
+
+/// Async version of `handle_connection`
+///
+/// Pipelined requests are answered in order. Returning will close connection.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, client_subject: Option<String>, cfg: Arc<Config>) {
+    let mut reader = RequestReader::new(Inbox::default());
+    reader.set_max_body_size(cfg.max_body_size);
+    let mut first = true;
+    loop {
+        let head = match read_head(&mut stream, &mut reader, first, &cfg).await {
+            Some(head) => head,
+            None => return,
+        };
+        first = false;
+
+        // handlers block on file system, body is received while handler runs
+        let (body_tx, body_rx) = mpsc::channel(BODY_QUEUE);
+        let body = BodyReceiver { rx: body_rx, piece: Vec::new(), pos: 0 };
+        let cfg_cp = Arc::clone(&cfg);
+        let client_subject = client_subject.clone();
+        let handler = tokio::task::spawn_blocking(move || respond(&head, body, client_subject, &cfg_cp));
+        let connected = pump_body(&mut stream, &mut reader, body_tx, &cfg).await;
+        let prepared = match handler.await {
+            Ok(Some(prepared)) => prepared,
+            // TCP will also be closed
+            Ok(None) => return,
+            Err(e) => {
+                println!("handler failed: {}, close TCP link.", e);
+                return
+            }
+        };
+        if !connected {
+            // handler has seen the broken body, its response is dropped
+            return
+        }
+        if !reader.trailers().is_empty() {
+            println!("request trailers: {:#?}", reader.trailers());
+        }
+        let keep_alive = prepared.keep_alive;
+        if let Err(e) = send(&mut stream, prepared, &cfg).await {
+            println!("fail to send response: {}, close TCP link.", e);
+            return
+        }
+        if !keep_alive {
+            return
+        }
+    }
+}
+
+/// Do TLS handshake on `stream`, like `listener::accept_tls` does
+async fn accept_tls(mut stream: TcpStream, acceptor: &SslAcceptor, cfg: &Config, peer: &str) -> Option<SslStream<TcpStream>> {
+    let mut first = [0u8; 1];
+    match check_first_byte(stream.peek(&mut first).await, first[0], peer) {
+        FirstByte::Tls => (),
+        FirstByte::PlainHttp => {
+            if cfg.tls.redirect_plain_http {
+                let mut reader = RequestReader::new(Inbox::default());
+                if let Some(head) = read_head(&mut stream, &mut reader, true, cfg).await {
+                    let prepared = prepare(listener::redirect_response(&head), false, false, cfg);
+                    let _ = send(&mut stream, prepared, cfg).await;
+                }
+            }
+            return None
+        }
+        FirstByte::Closed => return None,
+    }
+
+    let stream = Ssl::new(acceptor.context()).and_then(|ssl| SslStream::new(ssl, stream));
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("TLS handshake with {} failed: {}", peer, e);
+            return None
+        }
+    };
+    match Pin::new(&mut stream).accept().await {
+        Ok(()) => Some(stream),
+        Err(e) => {
+            println!("TLS handshake with {} failed: {}", peer, e);
+            None
+        }
+    }
+}
+
+/// Handshake with time limit, then handle connection
+async fn serve_tls(stream: TcpStream, acceptor: Arc<SslAcceptor>, cfg: Arc<Config>) {
+    let peer = peer_name(stream.peer_addr());
+    let handshake = accept_tls(stream, &acceptor, &cfg, &peer);
+    let stream = match cfg.tls.handshake_timeout {
+        0 => handshake.await,
+        secs => match tokio::time::timeout(Duration::from_secs(secs), handshake).await {
+            Ok(stream) => stream,
+            Err(_) => {
+                println!("TLS handshake with {} failed: timed out after {} secs", peer, secs);
+                None
+            }
+        },
+    };
+    if let Some(stream) = stream {
+        // client certificate does not change in a connection
+        let client_subject = tls::client_subject(stream.ssl());
+        if let Some(subject) = &client_subject {
+            println!("client certificate: {}", subject);
+        }
+        handle_connection(stream, client_subject, cfg).await
+    }
+}
+
+async fn accept_tcp(listener: TcpListener, acceptor: Option<SharedAcceptor>, cfg: Arc<Config>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_e) => continue, // connection failed
+        };
+        let cfg = Arc::clone(&cfg);
+        match &acceptor {
+            // certificates may be reloaded, always use the current acceptor
+            Some(acceptor) => tokio::spawn(serve_tls(stream, acceptor.current(), cfg)),
+            None => tokio::spawn(handle_connection(stream, None, cfg)),
+        };
+    }
+}
+
+async fn accept_unix(listener: UnixListener, cfg: Arc<Config>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_e) => continue, // connection failed
+        };
+        tokio::spawn(handle_connection(stream, None, Arc::clone(&cfg)));
+    }
+}
+
+/// Serve all listeners of `cfg`, in the tokio runtime of caller
+///
+/// Errors of binding are returned at once, then it runs forever.
+/// `acceptor` must be given if a listener serves HTTPS.
+pub async fn serve(cfg: Config, acceptor: Option<SharedAcceptor>) -> io::Result<()> {
+    let cfg = Arc::new(cfg);
+    let mut tasks = Vec::new();
+    for listener_cfg in &cfg.listeners {
+        let acceptor = match (listener_cfg.tls, &acceptor) {
+            (false, _) => None,
+            (true, Some(acceptor)) => Some(acceptor.clone()),
+            (true, None) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("TLS listener {} without certificate", listener_cfg))),
+        };
+        let described = |e: io::Error| io::Error::new(e.kind(), format!("fail to listen on {}: {}", listener_cfg, e));
+        match listener_cfg.kind {
+            ListenerKind::Tcp => {
+                for (addr, listener) in listener::bind_all(listener_cfg).map_err(described)? {
+                    listener.set_nonblocking(true)?;
+                    let listener = TcpListener::from_std(listener)?;
+                    println!("listening on {} ({}, async)", addr, if acceptor.is_some() { "https" } else { "http" });
+                    tasks.push(tokio::spawn(accept_tcp(listener, acceptor.clone(), Arc::clone(&cfg))));
+                }
+            }
+            ListenerKind::Unix => {
+                if acceptor.is_some() {
+                    return Err(described(io::Error::new(io::ErrorKind::InvalidInput, "TLS is not supported on Unix socket")))
+                }
+                let listener = listener::bind_unix(listener_cfg).map_err(described)?;
+                listener.set_nonblocking(true)?;
+                let listener = UnixListener::from_std(listener)?;
+                println!("listening on unix:{} (http, async)", listener_cfg.path);
+                tasks.push(tokio::spawn(accept_unix(listener, Arc::clone(&cfg))));
+            }
+        }
+    }
+    // listeners never return
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(())
+}
+
+/// Start a tokio runtime, then `serve` in it
+pub fn run(cfg: &Config, acceptor: Option<SharedAcceptor>) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(cfg.thread_number.max(1))
+        .build()?;
+    runtime.block_on(serve(cfg.clone(), acceptor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{status_lines, test_config, test_upload_config};
+
+    /// Run `handle_connection` on `input`, return everything it sends
+    fn output_of(input: &[u8], cfg: Config) -> Vec<u8> {
+        output_of_parts(&[input], Duration::from_secs(0), cfg)
+    }
+
+    /// Like `output_of`, but client pauses before sending each part after the first
+    fn output_of_parts(parts: &[&[u8]], pause: Duration, cfg: Config) -> Vec<u8> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let task = tokio::spawn(handle_connection(server, None, Arc::new(cfg)));
+            // small buffer of duplex makes the server wait for every few bytes
+            let (mut client_rx, mut client_tx) = tokio::io::split(client);
+            let parts: Vec<Vec<u8>> = parts.iter().map(|i| i.to_vec()).collect();
+            tokio::spawn(async move {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        tokio::time::sleep(pause).await;
+                    }
+                    client_tx.write_all(part).await?;
+                }
+                io::Result::Ok(())
+            });
+            let mut output = Vec::new();
+            client_rx.read_to_end(&mut output).await.unwrap();
+            // rejected connection is drained until client closes it
+            drop(client_rx);
+            task.await.unwrap();
+            output
+        })
+    }
+
+    #[test]
+    fn same_as_sync_server() {
+        let input = b"HEAD /none HTTP/1.1\r\n\r\nOPTIONS / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /hello.html HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let output = output_of(input, test_config());
+        assert_eq!(status_lines(&output), ["HTTP/1.1 404 NOT FOUND", "HTTP/1.1 204 No Content", "HTTP/1.1 200 OK"]);
+        let expected = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/page/hello.html")).unwrap();
+        assert!(output.ends_with(&expected));
+
+        // malformed request is rejected, then connection is closed
+        let output = output_of(b"GET / HTTP/1.1\r\nHost localhost\r\n\r\nGET / HTTP/1.1\r\n\r\n", test_config());
+        assert_eq!(status_lines(&output), ["HTTP/1.1 400 Bad Request"]);
+    }
+
+    #[test]
+    fn body_in_memory() {
+        let cfg = test_upload_config("body_in_memory");
+        let _ = fs::remove_file(std::path::Path::new(&cfg.root_dir).join("small.txt"));
+        let input = b"PUT /small.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloOPTIONS / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let output = String::from_utf8(output_of(input, cfg)).unwrap();
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        // body of the first response is exactly as long as it tells
+        let head_end = output.find("\r\n\r\n").unwrap() + 4;
+        let length: usize = output[..head_end].lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap().parse().unwrap();
+        assert!(length > 0);
+        assert!(output[head_end + length..].starts_with("HTTP/1.1 204 No Content\r\n"));
+    }
+
+    #[test]
+    fn stream_request_body() {
+        let cfg = Config { max_body_size: 200_000, ..test_upload_config("stream_request_body") };
+        let root_dir = std::path::Path::new(&cfg.root_dir);
+        let _ = fs::remove_file(root_dir.join("upload.bin"));
+        // body is much larger than the buffers between client and handler
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        let mut input = format!("POST /upload.bin HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", data.len()).into_bytes();
+        input.extend_from_slice(&data);
+        let output = output_of(&input, cfg.clone());
+        assert_eq!(status_lines(&output), ["HTTP/1.1 201 Created"]);
+        assert_eq!(fs::read(root_dir.join("upload.bin")).unwrap(), data);
+
+        // body not read by handler is not received before response
+        let input = b"GET /none HTTP/1.1\r\nContent-Length: 1000000\r\nConnection: close\r\n\r\n";
+        let output = output_of(input, cfg);
+        assert_eq!(status_lines(&output), ["HTTP/1.1 404 NOT FOUND"]);
+    }
+
+    #[test]
+    fn slow_request_body() {
+        let cfg = test_upload_config("slow_request_body");
+        let _ = fs::remove_file(std::path::Path::new(&cfg.root_dir).join("slow.txt"));
+        let parts: [&[u8]; 2] = [b"PUT /slow.txt HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\n", b"hello"];
+        // body slower than keep-alive timeout is still received
+        let pause = Duration::from_millis(1500);
+        let output = output_of_parts(&parts, pause, cfg.clone());
+        assert_eq!(status_lines(&output), ["HTTP/1.1 201 Created"]);
+
+        // body slower than request timeout closes connection without response
+        let output = output_of_parts(&parts, pause, Config { request_timeout: 1, ..cfg });
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn chunked_file_body() {
+        let cfg = Config { chunk: true, chunk_size: 1000, ..test_config() };
+        let output = output_of(b"GET /test.jpg HTTP/1.1\r\nConnection: close\r\n\r\n", cfg);
+        assert_eq!(status_lines(&output), ["HTTP/1.1 200 OK"]);
+        // decode body by the request reader, a chunked request has the same framing
+        let head_end = output.windows(4).position(|i| i == b"\r\n\r\n").unwrap() + 4;
+        let mut raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        raw.extend_from_slice(&output[head_end..]);
+        let mut decoder = RequestReader::new(&raw[..]);
+        decoder.read_request().unwrap().unwrap();
+        let mut body = Vec::new();
+        decoder.body().read_to_end(&mut body).unwrap();
+        assert_eq!(body, fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/page/test.jpg")).unwrap());
+    }
+}
@@ -2,14 +2,75 @@
 //! 
 //! ref: https://doc.rust-lang.org/book/ch20-02-multithreaded.html
 //! 
+//! Jobs wait in a bounded queue, `execute` blocks when it is full,
+//! `try_execute` gives up instead.
+//! 
+//! A panic in a job is caught and logged, the worker goes on with the next job.
+//! If a worker thread dies anyway, a new one takes its place.
+//! 
+//! Pool starts with `min` workers. New workers are spawned when queued jobs
+//! outnumber idle workers, up to `max`. Workers idle for `idle_timeout` exit,
+//! until `min` are left. Time spent waiting for the queue lock counts as idle,
+//! so workers which become idle together also exit together.
+//! 
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
+    sender: mpsc::SyncSender<Message>,
+}
+
+/// State shared by pool and workers
+struct Shared {
+    receiver: Mutex<mpsc::Receiver<Message>>,
+    /// Threads of workers, finished ones are removed when a new one is spawned
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+    state: Mutex<State>,
+    min: usize,
+    max: usize,
+    /// `None` means idle workers never exit
+    idle_timeout: Option<Duration>,
+}
+
+/// Worker counters
+struct State {
+    /// Number of workers, including the ones being spawned
+    size: usize,
+    /// Number of workers waiting for a job
+    idle: usize,
+    /// Number of workers spawned but not waiting for a job yet
+    starting: usize,
+    /// Number of jobs sent but not taken by a worker yet
+    pending: usize,
+    /// Id of the next new worker
+    next_id: usize,
+}
+
+/// Lock `mutex` even if a thread panicked while holding it
+///
+/// Data behind the locks of pool is never left half updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Message of a panic payload, which is usually a string
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -22,84 +83,338 @@ enum Message {
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
-    /// The size is the number of threads in the pool.
+    /// The number of threads in the pool is between `min` and `max`,
+    /// `min` is raised to 1 and lowered to `max` if needed.
+    /// At most `queue_size` jobs wait for a free worker.
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+    /// The `new` function will panic if `max` is zero.
+    pub fn new(min: usize, max: usize, queue_size: usize, idle_timeout: Option<Duration>) -> ThreadPool {
+        assert!(max > 0);
+        let min = min.clamp(1, max);
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
 
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(size);
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            threads: Mutex::new(Vec::with_capacity(min)),
+            state: Mutex::new(State { size: min, idle: 0, starting: min, pending: 0, next_id: min }),
+            min,
+            max,
+            idle_timeout,
+        });
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        for id in 0..min {
+            Worker::spawn(id, Arc::clone(&shared));
         }
 
-        ThreadPool { workers, sender }
+        ThreadPool { shared, sender }
+    }
+
+    /// Current number of workers
+    pub fn size(&self) -> usize {
+        lock(&self.shared.state).size
+    }
+
+    /// Count a new job, spawn workers while queued jobs outnumber idle ones
+    ///
+    /// Workers being spawned are counted as idle, for they will take a job soon.
+    fn grow(&self) {
+        lock(&self.shared.state).pending += 1;
+        loop {
+            let id = {
+                let mut state = lock(&self.shared.state);
+                if state.pending <= state.idle + state.starting || state.size >= self.shared.max {
+                    return;
+                }
+                state.size += 1;
+                state.starting += 1;
+                state.next_id += 1;
+                state.next_id - 1
+            };
+            if !Worker::spawn(id, Arc::clone(&self.shared)) {
+                return;
+            }
+        }
     }
 
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
+        self.grow();
         let job = Box::new(f);
 
         self.sender.send(Message::NewJob(job)).unwrap();
     }
+
+    /// Execute `f` unless the queue is full
+    ///
+    /// Return false if `f` is dropped without running.
+    pub fn try_execute<F>(&self, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.grow();
+        let job = Box::new(f);
+
+        match self.sender.try_send(Message::NewJob(job)) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                lock(&self.shared.state).pending -= 1;
+                false
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => panic!("all workers are dead"),
+        }
+    }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         println!("Sending terminate message to all workers.");
 
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        // dead workers have been replaced, tell every living one
+        let size = self.size();
+        for _ in 0..size {
+            if self.sender.send(Message::Terminate).is_err() {
+                break;
+            }
         }
 
         println!("Shutting down all workers.");
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
+        let threads: Vec<_> = lock(&self.shared.threads).drain(..).collect();
+        for thread in threads {
+            // dead workers return Err, they have been logged
+            let _ = thread.join();
         }
     }
 }
 
+/// Worker thread, a new one is spawned when it dies
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    shared: Arc<Shared>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+    /// Spawn worker `id` in a new thread
+    ///
+    /// It must have been counted in `State.size` and `State.starting`.
+    /// Return false if thread can not be spawned.
+    fn spawn(id: usize, shared: Arc<Shared>) -> bool {
+        let worker = Worker { id, shared: Arc::clone(&shared) };
+        match thread::Builder::new().spawn(move || worker.run()) {
+            Ok(thread) => {
+                let mut threads = lock(&shared.threads);
+                threads.retain(|i| !i.is_finished());
+                threads.push(thread);
+                true
+            }
+            Err(e) => {
+                println!("Worker {} can not be spawned: {}", id, e);
+                let mut state = lock(&shared.state);
+                state.size -= 1;
+                state.starting -= 1;
+                false
+            }
+        }
+    }
+
+    fn run(&self) {
+        lock(&self.shared.state).starting -= 1;
+        let mut idle_since = Instant::now();
+        loop {
+            lock(&self.shared.state).idle += 1;
+            let message = {
+                // only one worker waits on the queue, others wait for the lock
+                let receiver = lock(&self.shared.receiver);
+                match self.shared.idle_timeout {
+                    Some(timeout) => receiver.recv_timeout(timeout.saturating_sub(idle_since.elapsed())),
+                    None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                }
+            };
+            let mut state = lock(&self.shared.state);
+            state.idle -= 1;
+
+            let message = match message {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    // a job may be sent right after timeout, stay if no other worker will take it
+                    if state.size > self.shared.min && state.pending <= state.idle + state.starting {
+                        state.size -= 1;
+                        println!("Worker {} is idle, exit; {} workers left.", self.id, state.size);
+                        break;
+                    }
+                    idle_since = Instant::now();
+                    continue;
+                }
+                // pool is gone
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    state.size -= 1;
+                    break;
+                }
+            };
 
             match message {
                 Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
-                    job();
-                    println!("Worker {} finished its job.", id);
+                    state.pending -= 1;
+                    drop(state);
+                    println!("Worker {} got a job; executing.", self.id);
+                    match panic::catch_unwind(AssertUnwindSafe(job)) {
+                        Ok(()) => println!("Worker {} finished its job.", self.id),
+                        Err(payload) => println!("Worker {} panicked in its job: {}", self.id, panic_message(&*payload)),
+                    }
+                    idle_since = Instant::now();
                 }
                 Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
+                    println!("Worker {} was told to terminate.", self.id);
+                    state.size -= 1;
 
                     break;
                 }
             }
+        }
+    }
+}
+
+impl Drop for Worker {
+    /// Replace a worker killed by panic, so the pool never shrinks
+    ///
+    /// The new one takes the place of the dead one in `State.size`.
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("Worker {} died, spawning a new one.", self.id);
+            lock(&self.shared.state).starting += 1;
+            Worker::spawn(self.id, Arc::clone(&self.shared));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_queue() {
+        let pool = ThreadPool::new(1, 1, 1, None);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        // worker is busy
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
         });
+        started_rx.recv().unwrap();
+        // queue has room for one job only
+        let (done_tx, done_rx) = mpsc::channel();
+        let done_tx_cp = done_tx.clone();
+        assert!(pool.try_execute(move || done_tx_cp.send(1).unwrap()));
+        assert!(!pool.try_execute(move || done_tx.send(2).unwrap()));
+        release_tx.send(()).unwrap();
+        assert_eq!(done_rx.recv().unwrap(), 1);
+        // rejected job is dropped, sender of it is gone
+        assert!(done_rx.recv().is_err());
+    }
+
+    #[test]
+    fn panic_in_job() {
+        let pool = ThreadPool::new(1, 1, 1, None);
+        pool.execute(|| panic!("test panic"));
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        assert!(rx.recv().is_ok());
+    }
+
+    #[test]
+    fn respawn_dead_worker() {
+        let pool = ThreadPool::new(1, 1, 1, None);
+        // a worker killed outside of job, e.g. by a panic while logging
+        let shared = Arc::clone(&pool.shared);
+        lock(&shared.state).size += 1;
+        let dead = thread::spawn(move || {
+            let _worker = Worker { id: 1, shared };
+            panic!("worker killed");
+        });
+        assert!(dead.join().is_err());
+        assert_eq!(lock(&pool.shared.threads).len(), 2);
+        assert_eq!(pool.size(), 2);
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(()).unwrap());
+        }
+        for _ in 0..4 {
+            assert!(rx.recv().is_ok());
+        }
+    }
 
-        Worker {
-            id,
-            thread: Some(thread),
+    #[test]
+    fn grow_and_shrink() {
+        let pool = ThreadPool::new(1, 7, 0, Some(Duration::from_millis(300)));
+        assert_eq!(pool.size(), 1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        // every job keeps a worker busy
+        for _ in 0..7 {
+            let started_tx = started_tx.clone();
+            let release_rx = Arc::clone(&release_rx);
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                let _ = lock(&release_rx).recv();
+            });
+        }
+        for _ in 0..7 {
+            started_rx.recv().unwrap();
+        }
+        assert_eq!(pool.size(), 7);
+        // max is reached, no more worker
+        assert!(!pool.try_execute(|| ()));
+        assert_eq!(pool.size(), 7);
+        drop(release_tx);
+        // idle workers exit together, not one per timeout
+        let released = Instant::now();
+        while pool.size() > 1 && released.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
         }
+        assert_eq!(pool.size(), 1);
+        assert!(released.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn grow_on_burst() {
+        let pool = ThreadPool::new(1, 64, 64, None);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        // jobs are queued before any worker takes one
+        for _ in 0..8 {
+            let started_tx = started_tx.clone();
+            let release_rx = Arc::clone(&release_rx);
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                let _ = lock(&release_rx).recv();
+            });
+        }
+        // no job waits behind a busy worker
+        for _ in 0..8 {
+            assert!(started_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+        assert_eq!(pool.size(), 8);
+        drop(release_tx);
+    }
+
+    #[test]
+    fn lock_poisoned() {
+        let mutex = Arc::new(Mutex::new(1));
+        let mutex_cp = Arc::clone(&mutex);
+        let _ = thread::spawn(move || {
+            let _guard = mutex_cp.lock();
+            panic!("poison mutex");
+        }).join();
+        assert!(mutex.is_poisoned());
+        assert_eq!(*lock(&mutex), 1);
     }
 }